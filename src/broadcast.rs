@@ -1,14 +1,12 @@
 use async_trait::async_trait;
+use gossip_glomers::crdt::GSet;
+use gossip_glomers::gossip::Gossip;
+use gossip_glomers::hlc::Hlc;
 use maelstrom::protocol::Message;
 use maelstrom::{done, Node, Result, Runtime};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use std::sync::Mutex;
-use std::time::Duration;
-use std::usize;
-use tokio;
-use tokio_context::context::Context;
 pub(crate) fn main() {
     let _ = Runtime::init(try_main());
 }
@@ -18,48 +16,18 @@ async fn try_main() -> Result<()> {
     Runtime::new().with_handler(handler).run().await
 }
 
-#[derive(Clone, Default)]
+#[derive(Clone)]
 struct Handler {
-    inner: Arc<Mutex<Inner>>,
-}
-
-struct Inner {
-    counter: usize,
-    messages: HashSet<usize>,
-    broadcast: HashSet<usize>,
-    topology: HashMap<String, Vec<String>>,
-}
-
-impl Inner {
-    fn new() -> Self {
-        Self {
-            counter: 0,
-            messages: HashSet::new(),
-            broadcast: HashSet::new(),
-            topology: HashMap::new(),
-        }
-    }
-
-    fn neighbors(&self, node_id: &str) -> &Vec<String> {
-        self.topology.get(node_id).unwrap()
-    }
-}
-
-impl Default for Inner {
-    fn default() -> Self {
-        Self {
-            counter: 0,
-            messages: HashSet::new(),
-            broadcast: HashSet::new(),
-            topology: HashMap::new(),
-        }
-    }
+    clock: Arc<Hlc>,
+    messages: Gossip<GSet<usize>>,
 }
 
 impl Handler {
     fn new() -> Self {
+        let clock = Arc::new(Hlc::new());
         Self {
-            inner: Arc::new(Mutex::new(Inner::new())),
+            clock: clock.clone(),
+            messages: Gossip::new().with_clock(clock),
         }
     }
 }
@@ -70,50 +38,7 @@ impl Node for Handler {
         let msg: Result<Request> = req.body.as_obj();
         match msg {
             Ok(Request::Init {}) => {
-                let (r0, h0) = (runtime.clone(), self.clone());
-                tokio::spawn(async move {
-                    loop {
-                        tokio::time::sleep(Duration::from_millis(150)).await;
-                        let (neighbors, messages, broadcast) = {
-                            let state = h0.inner.lock().unwrap();
-                            let n = state.neighbors(r0.node_id()).clone();
-                            let m = state.messages.clone();
-                            let b = state.broadcast.clone();
-                            (n, m, b)
-                        };
-                        let diff = messages
-                            .difference(&broadcast)
-                            .copied()
-                            .collect::<HashSet<usize>>();
-
-                        if diff.is_empty() {
-                            continue;
-                        }
-
-                        for n in neighbors {
-                            let r1 = r0.clone();
-                            let diff0 = diff.clone();
-                            tokio::spawn(async move {
-                                loop {
-                                    let msg = Request::BatchBroadcast {
-                                        message: diff0.iter().copied().collect::<Vec<usize>>(),
-                                    };
-                                    let (ctx, _handler) =
-                                        Context::with_timeout(Duration::from_millis(400));
-                                    let message = r1.call(ctx, n.clone(), msg).await;
-                                    match message {
-                                        Ok(_) => break,
-                                        Err(_) => {}
-                                    }
-                                }
-                            });
-                        }
-                        {
-                            let mut state = h0.inner.lock().unwrap();
-                            state.broadcast = state.broadcast.union(&diff).copied().collect();
-                        }
-                    }
-                });
+                self.messages.start(runtime.clone());
             }
 
             Ok(Request::Echo {}) => {
@@ -121,54 +46,29 @@ impl Node for Handler {
                 return runtime.reply(req, echo).await;
             }
             Ok(Request::Generate {}) => {
-                let msg_id = {
-                    let mut inner = self.inner.lock().unwrap();
-                    let last_count = inner.counter;
-                    inner.counter += 1;
-                    last_count
-                };
-                let id = format!("{}-{}", runtime.node_id(), msg_id);
+                let id = format!("{}-{}", runtime.node_id(), self.clock.now());
                 let generate = Response::GenerateOk { id };
                 return runtime.reply(req, generate).await;
             }
             Ok(Request::Broadcast { message }) => {
-                {
-                    let mut inner = self.inner.lock().unwrap();
-                    let msg_set = &mut inner.messages;
-                    msg_set.insert(message)
-                };
+                self.messages.update(|messages| messages.add(message));
 
                 let broadcast_response = Response::BroadcastOk {};
                 return runtime.reply(req, broadcast_response).await;
             }
-            Ok(Request::BatchBroadcast { message }) => {
-                {
-                    let mut inner = self.inner.lock().unwrap();
-                    let msg_set = &mut inner.messages;
-                    for m in message {
-                        msg_set.insert(m);
-                    }
-                };
-                let broadcast_response = Response::BatchBroadcastOk {};
-                return runtime.reply(req, broadcast_response).await;
-            }
             Ok(Request::Read {}) => {
-                let values = {
-                    let inner = self.inner.lock().unwrap();
-                    inner.messages.clone()
-                };
+                let values = self.messages.value();
                 let read_response = Response::ReadOk { messages: values };
                 return runtime.reply(req, read_response).await;
             }
             Ok(Request::Topology { topology }) => {
-                {
-                    let mut inner = self.inner.lock().unwrap();
-                    inner.topology = topology;
-                };
+                if let Some(neighbours) = topology.get(runtime.node_id()) {
+                    self.messages.set_neighbours(neighbours.clone());
+                }
                 let topo_response = Response::TopologyOk {};
                 return runtime.reply(req, topo_response).await;
             }
-            _ => {}
+            Err(_) => return self.messages.process(runtime, req).await,
         }
         done(runtime, req)
     }
@@ -183,9 +83,6 @@ enum Request {
     Broadcast {
         message: usize,
     },
-    BatchBroadcast {
-        message: Vec<usize>,
-    },
     Read {},
     Topology {
         topology: HashMap<String, Vec<String>>,
@@ -194,11 +91,11 @@ enum Request {
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "snake_case", tag = "type")]
+#[allow(clippy::enum_variant_names)]
 enum Response {
     EchoOk {},
     GenerateOk { id: String },
     BroadcastOk {},
-    BatchBroadcastOk {},
     ReadOk { messages: HashSet<usize> },
     TopologyOk {},
 }
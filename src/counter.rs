@@ -1,30 +1,87 @@
 use async_trait::async_trait;
-use maelstrom::kv::{seq_kv, Storage, KV};
+use gossip_glomers::crdt::GCounter;
+use gossip_glomers::gossip::Gossip;
+use gossip_glomers::kv::{retry, Kv};
+use maelstrom::kv::seq_kv;
 use maelstrom::protocol::Message;
 use maelstrom::{done, Node, Result, Runtime};
-use rand;
 use serde::{Deserialize, Serialize};
+use std::env;
 use std::sync::Arc;
-use std::usize;
-use tokio_context::context::Context;
 pub(crate) fn main() {
     let _ = Runtime::init(try_main());
 }
 
 async fn try_main() -> Result<()> {
     let runtime = Runtime::new();
-    let handler = Arc::new(Handler::new(runtime.clone()));
+    let handler = Arc::new(Handler::new(runtime.clone())?);
     runtime.with_handler(handler).run().await
 }
 
 #[derive(Clone)]
 struct Handler {
-    s: Storage,
+    counter: Counter,
+}
+
+/// Where the counts live.
+#[derive(Clone)]
+enum Counter {
+    /// A GCounter on every node, gossiped to the others. Adds and reads are
+    /// local, and every node reads the total once gossip reaches it.
+    Gossip(Gossip<GCounter>),
+    /// One seq-kv key per node, which only that node adds to. An add costs a
+    /// read and a cas, and a read a round trip per node.
+    SeqKv(Kv),
 }
 
 impl Handler {
-    fn new(runtime: Runtime) -> Self {
-        Self { s: seq_kv(runtime) }
+    /// Picks where to keep the counts by the `COUNTER_MODE` environment
+    /// variable, `gossip` or `seq-kv`, defaulting to `gossip`.
+    fn new(runtime: Runtime) -> Result<Self> {
+        let mode = env::var("COUNTER_MODE").unwrap_or_else(|_| "gossip".to_string());
+        let counter = match mode.as_str() {
+            "gossip" => Counter::Gossip(Gossip::new()),
+            "seq-kv" => Counter::SeqKv(Kv::new(seq_kv(runtime))),
+            _ => return Err(format!("unknown COUNTER_MODE {:?}", mode).into()),
+        };
+        Ok(Self { counter })
+    }
+
+    async fn read(&self, runtime: &Runtime) -> Result<u64> {
+        match &self.counter {
+            Counter::Gossip(counter) => Ok(counter.value()),
+            Counter::SeqKv(s) => {
+                let mut value = 0;
+                for n in runtime.nodes() {
+                    // A write of our own first, so the read that follows
+                    // can't see an older state than the last one we saw.
+                    let x = rand::random::<u64>();
+                    let key = format!("random_{:}_{:}", runtime.node_id(), x);
+                    let _ = s.put(key, x).await;
+                    let count: u64 = s.get_or(n.to_string(), 0).await?;
+                    value += count;
+                }
+                Ok(value)
+            }
+        }
+    }
+
+    /// Adds `delta` to this node's count. In seq-kv, a cas that timed out
+    /// fails with crash rather than adding twice.
+    async fn add(&self, runtime: &Runtime, delta: u64) -> Result<()> {
+        let node_id = runtime.node_id();
+        match &self.counter {
+            Counter::Gossip(counter) => counter.update(|counter| counter.inc(node_id, delta)),
+            Counter::SeqKv(s) => {
+                let key = node_id.to_string();
+                retry(|| async {
+                    let count: u64 = s.get_or(key.clone(), 0).await?;
+                    s.cas(key.clone(), count, count + delta, true).await
+                })
+                .await?;
+            }
+        }
+        Ok(())
     }
 }
 
@@ -33,40 +90,27 @@ impl Node for Handler {
     async fn process(&self, runtime: Runtime, req: Message) -> Result<()> {
         let msg: Result<Request> = req.body.as_obj();
         match msg {
-            Ok(Request::Read {}) => {
-                let mut value: usize = 0;
-                for n in runtime.nodes() {
-                    let (ctx, _handler) = Context::new();
-                    let x = rand::random::<usize>();
-                    let s = format!("random_{:}_{:}", runtime.node_id(), x);
-                    let _ = self.s.put(ctx, s, x).await;
-                    let (ctx, _handler) = Context::new();
-                    let val = match self.s.get(ctx, n.to_string()).await {
-                        Ok(val) => val,
-                        Err(_) => 0,
-                    };
-                    value += val;
+            Ok(Request::Init {}) => {
+                if let Counter::Gossip(counter) = &self.counter {
+                    counter.start(runtime.clone());
                 }
-
+            }
+            Ok(Request::Read {}) => {
+                let value = self.read(&runtime).await?;
                 let read_response = Response::ReadOk { value };
                 return runtime.reply(req, read_response).await;
             }
             Ok(Request::Add { delta }) => {
-                let (ctx, _handler) = Context::new();
-                let val = match self.s.get(ctx, runtime.node_id().to_string()).await {
-                    Ok(val) => val,
-                    Err(_) => 0,
-                };
-                let (ctx, _handler) = Context::new();
-                self.s
-                    .cas(ctx, runtime.node_id().to_string(), val, val + delta, true)
-                    .await
-                    .unwrap();
+                self.add(&runtime, delta).await?;
 
                 let response = Response::AddOk {};
                 return runtime.reply(req, response).await;
             }
-            _ => {}
+            Err(_) => {
+                if let Counter::Gossip(counter) = &self.counter {
+                    return counter.process(runtime, req).await;
+                }
+            }
         }
         done(runtime, req)
     }
@@ -77,13 +121,12 @@ impl Node for Handler {
 enum Request {
     Init {},
     Read {},
-    Add { delta: usize },
+    Add { delta: u64 },
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "snake_case", tag = "type")]
 enum Response {
-    ReadOk { value: usize },
+    ReadOk { value: u64 },
     AddOk {},
 }
-
@@ -0,0 +1,124 @@
+//! Conflict-free replicated data types.
+//!
+//! Every replica of a CRDT takes updates locally, without asking any other,
+//! and replicas merge each other's states. Merging is commutative,
+//! associative and idempotent, so replicas that have seen the same updates
+//! have the same state, whatever order and however many times they merged
+//! them in.
+//!
+//! Rather than their whole state, replicas send each other deltas: the part
+//! of a state another replica is missing, see [`Crdt::delta`]. Every CRDT
+//! is a gossip [`State`], so a [`Gossip`](crate::gossip::Gossip) engine can
+//! host a replica on a node and keep its neighbours up to date this way.
+
+use crate::gossip::State;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::hash::Hash;
+
+mod counter;
+mod lww;
+mod set;
+
+pub use counter::{GCounter, PNCounter};
+pub use lww::{LwwMap, LwwRegister, Stamp};
+pub use set::{GSet, ORSet, TwoPSet};
+
+pub trait Crdt:
+    Clone + Default + PartialEq + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+    /// What the replica holds, as its users see it.
+    type Value;
+
+    /// Merges `other` into this replica.
+    fn merge(&mut self, other: &Self);
+
+    /// The smallest state that brings `since` up to this one: merging it
+    /// into `since` has the same result as merging all of this state. The
+    /// default state if `since` has everything already.
+    fn delta(&self, since: &Self) -> Self;
+
+    fn value(&self) -> Self::Value;
+}
+
+/// What CRDTs can hold: sets hash their elements, and replicas send them to
+/// one another.
+pub trait Element:
+    Clone + Eq + Hash + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+}
+
+impl<T> Element for T where
+    T: Clone + Eq + Hash + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+}
+
+impl<C: Crdt> State for C {
+    fn merge(&mut self, other: &Self) {
+        Crdt::merge(self, other)
+    }
+
+    fn diff(&self, since: &Self) -> Self {
+        self.delta(since)
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::Crdt;
+    use rand::rngs::ThreadRng;
+    use rand::Rng;
+    use std::fmt::Debug;
+
+    const NODES: [&str; 3] = ["n0", "n1", "n2"];
+
+    /// The states three replicas go through while each runs `update` or
+    /// merges another's state, at random.
+    pub(crate) fn states<C: Crdt>(mut update: impl FnMut(&mut C, &str, &mut ThreadRng)) -> Vec<C> {
+        let mut rng = rand::thread_rng();
+        let mut replicas = vec![C::default(); NODES.len()];
+        let mut states = vec![C::default()];
+        for _ in 0..30 {
+            let i = rng.gen_range(0..NODES.len());
+            match rng.gen_bool(0.7) {
+                true => update(&mut replicas[i], NODES[i], &mut rng),
+                false => {
+                    let other = replicas[rng.gen_range(0..NODES.len())].clone();
+                    replicas[i].merge(&other);
+                }
+            }
+            states.push(replicas[i].clone());
+        }
+        states
+    }
+
+    fn merged<C: Crdt>(a: &C, b: &C) -> C {
+        let mut a = a.clone();
+        a.merge(b);
+        a
+    }
+
+    /// Checks that merging is commutative, associative and idempotent over
+    /// `states`, and that a delta brings any state up to date.
+    pub(crate) fn check_laws<C: Crdt + Debug>(states: &[C]) {
+        for a in states {
+            assert_eq!(merged(a, a), *a, "idempotent");
+            assert_eq!(a.delta(a), C::default(), "nothing new");
+            for b in states {
+                let ab = merged(a, b);
+                assert_eq!(ab, merged(b, a), "commutative");
+                assert_eq!(merged(b, &a.delta(b)), ab, "delta");
+                assert_eq!(a.delta(&ab), C::default(), "nothing new");
+                for c in states {
+                    let left = merged(&ab, c);
+                    let right = merged(a, &merged(b, c));
+                    assert_eq!(left, right, "associative");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use super::Crdt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// A counter that only goes up. Each node counts its own increments, and the
+/// value is the sum of the counts.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GCounter {
+    counts: HashMap<String, u64>,
+}
+
+impl GCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn inc(&mut self, node: &str, n: u64) {
+        *self.counts.entry(node.to_string()).or_default() += n;
+    }
+}
+
+impl Crdt for GCounter {
+    type Value = u64;
+
+    fn merge(&mut self, other: &Self) {
+        for (node, n) in &other.counts {
+            let count = self.counts.entry(node.clone()).or_default();
+            *count = (*count).max(*n);
+        }
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        let counts = self
+            .counts
+            .iter()
+            .filter(|(node, n)| since.counts.get(*node).is_none_or(|m| *n > m));
+        Self {
+            counts: counts.map(|(node, n)| (node.clone(), *n)).collect(),
+        }
+    }
+
+    fn value(&self) -> u64 {
+        self.counts.values().sum()
+    }
+}
+
+/// A counter that goes both ways, as one counter of increments and one of
+/// decrements.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PNCounter {
+    inc: GCounter,
+    dec: GCounter,
+}
+
+impl PNCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, node: &str, delta: i64) {
+        match delta >= 0 {
+            true => self.inc.inc(node, delta as u64),
+            false => self.dec.inc(node, delta.unsigned_abs()),
+        }
+    }
+}
+
+impl Crdt for PNCounter {
+    type Value = i64;
+
+    fn merge(&mut self, other: &Self) {
+        self.inc.merge(&other.inc);
+        self.dec.merge(&other.dec);
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        Self {
+            inc: self.inc.delta(&since.inc),
+            dec: self.dec.delta(&since.dec),
+        }
+    }
+
+    fn value(&self) -> i64 {
+        self.inc.value() as i64 - self.dec.value() as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crdt::tests::{check_laws, states};
+    use rand::Rng;
+
+    #[test]
+    fn g_counter_laws() {
+        check_laws(&states(|c: &mut GCounter, node, rng| {
+            c.inc(node, rng.gen_range(1..5))
+        }));
+    }
+
+    #[test]
+    fn pn_counter_laws() {
+        check_laws(&states(|c: &mut PNCounter, node, rng| {
+            c.add(node, rng.gen_range(-5..5))
+        }));
+    }
+
+    #[test]
+    fn counts_of_every_node_add_up() {
+        let (mut a, mut b) = (PNCounter::new(), PNCounter::new());
+        a.add("n0", 5);
+        a.add("n0", -2);
+        b.add("n1", -4);
+        a.merge(&b);
+        b.merge(&a);
+        assert_eq!((a.value(), b.value()), (-1, -1));
+    }
+}
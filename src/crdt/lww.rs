@@ -0,0 +1,189 @@
+use super::{Crdt, Element};
+use crate::hlc::Timestamp;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use std::collections::HashMap;
+
+/// When a value was written, and by which node, to break ties between
+/// writes with the same timestamp.
+pub type Stamp = (Timestamp, String);
+
+/// A register that keeps the last value written, by the writes' stamps.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct LwwRegister<T: Element> {
+    entry: Option<(Stamp, T)>,
+}
+
+impl<T: Element> LwwRegister<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Writes `value`, unless the register holds a later write already.
+    pub fn set(&mut self, value: T, stamp: Stamp) {
+        if self.stamp() < Some(&stamp) {
+            self.entry = Some((stamp, value));
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.entry.as_ref().map(|(_, value)| value)
+    }
+
+    /// The stamp of the last write, if there was one.
+    pub fn stamp(&self) -> Option<&Stamp> {
+        self.entry.as_ref().map(|(stamp, _)| stamp)
+    }
+}
+
+impl<T: Element> Default for LwwRegister<T> {
+    fn default() -> Self {
+        Self { entry: None }
+    }
+}
+
+impl<T: Element> Crdt for LwwRegister<T> {
+    type Value = Option<T>;
+
+    fn merge(&mut self, other: &Self) {
+        if let Some((stamp, value)) = &other.entry {
+            self.set(value.clone(), stamp.clone());
+        }
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        match self.stamp() > since.stamp() {
+            true => self.clone(),
+            false => Self::default(),
+        }
+    }
+
+    fn value(&self) -> Option<T> {
+        self.get().cloned()
+    }
+}
+
+/// A map with a last-writer-wins register for each key. Removing a key
+/// writes a tombstone, so that an older write doesn't bring it back.
+#[serde_as]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct LwwMap<K: Element, V: Element> {
+    #[serde_as(as = "Vec<(_, _)>")]
+    entries: HashMap<K, LwwRegister<Option<V>>>,
+}
+
+impl<K: Element, V: Element> LwwMap<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, key: K, value: V, stamp: Stamp) {
+        self.entries.entry(key).or_default().set(Some(value), stamp);
+    }
+
+    pub fn remove(&mut self, key: K, stamp: Stamp) {
+        self.entries.entry(key).or_default().set(None, stamp);
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key)?.get()?.as_ref()
+    }
+
+    /// The stamp of the last write to `key`, or of its removal.
+    pub fn stamp(&self, key: &K) -> Option<&Stamp> {
+        self.entries.get(key)?.stamp()
+    }
+}
+
+impl<K: Element, V: Element> Default for LwwMap<K, V> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Element, V: Element> Crdt for LwwMap<K, V> {
+    type Value = HashMap<K, V>;
+
+    fn merge(&mut self, other: &Self) {
+        for (key, register) in &other.entries {
+            self.entries.entry(key.clone()).or_default().merge(register);
+        }
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        let mut entries = HashMap::new();
+        for (key, register) in &self.entries {
+            let delta = match since.entries.get(key) {
+                Some(seen) => register.delta(seen),
+                None => register.clone(),
+            };
+            if delta.stamp().is_some() {
+                entries.insert(key.clone(), delta);
+            }
+        }
+        Self { entries }
+    }
+
+    fn value(&self) -> HashMap<K, V> {
+        let entries = self.entries.iter().filter_map(|(key, register)| {
+            let value = register.get()?.as_ref()?;
+            Some((key.clone(), value.clone()))
+        });
+        entries.collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crdt::tests::{check_laws, states};
+    use rand::rngs::ThreadRng;
+    use rand::Rng;
+
+    /// Stamps from a clock per node, which may tie with other nodes'.
+    fn clock() -> impl FnMut(&str, &mut ThreadRng) -> Stamp {
+        let mut clocks = HashMap::<String, u64>::new();
+        move |node, rng| {
+            let t = clocks.entry(node.to_string()).or_default();
+            *t += rng.gen_range(1..3);
+            (Timestamp(*t, 0), node.to_string())
+        }
+    }
+
+    #[test]
+    fn lww_register_laws() {
+        let mut stamp = clock();
+        check_laws(&states(|r: &mut LwwRegister<u8>, node, rng| {
+            let stamp = stamp(node, rng);
+            r.set(rng.gen(), stamp)
+        }));
+    }
+
+    #[test]
+    fn lww_map_laws() {
+        let mut stamp = clock();
+        check_laws(&states(|m: &mut LwwMap<u8, u8>, node, rng| {
+            let (key, stamp) = (rng.gen_range(0..5), stamp(node, rng));
+            match rng.gen_bool(0.7) {
+                true => m.set(key, rng.gen(), stamp),
+                false => m.remove(key, stamp),
+            }
+        }));
+    }
+
+    #[test]
+    fn later_write_wins_and_ties_go_to_the_larger_node() {
+        let mut a = LwwMap::new();
+        a.set(1, 'a', (Timestamp(2, 0), "n0".to_string()));
+        a.set(1, 'b', (Timestamp(1, 0), "n1".to_string()));
+        a.set(2, 'a', (Timestamp(1, 0), "n0".to_string()));
+        a.set(2, 'b', (Timestamp(1, 0), "n1".to_string()));
+        a.remove(3, (Timestamp(2, 0), "n0".to_string()));
+        a.set(3, 'a', (Timestamp(1, 0), "n0".to_string()));
+        assert_eq!(a.value(), HashMap::from([(1, 'a'), (2, 'b')]));
+    }
+}
@@ -0,0 +1,256 @@
+use super::{Crdt, Element};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use std::collections::{HashMap, HashSet};
+
+/// A set that only grows. Merging is union.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct GSet<T: Element> {
+    elements: HashSet<T>,
+}
+
+impl<T: Element> GSet<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, element: T) {
+        self.elements.insert(element);
+    }
+
+    pub fn contains(&self, element: &T) -> bool {
+        self.elements.contains(element)
+    }
+}
+
+impl<T: Element> Default for GSet<T> {
+    fn default() -> Self {
+        Self {
+            elements: HashSet::new(),
+        }
+    }
+}
+
+impl<T: Element> Crdt for GSet<T> {
+    type Value = HashSet<T>;
+
+    fn merge(&mut self, other: &Self) {
+        self.elements.extend(other.elements.iter().cloned());
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        Self {
+            elements: self.elements.difference(&since.elements).cloned().collect(),
+        }
+    }
+
+    fn value(&self) -> HashSet<T> {
+        self.elements.clone()
+    }
+}
+
+/// A set elements can be removed from, once: a removed element stays
+/// removed, even if it gets added again.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct TwoPSet<T: Element> {
+    added: GSet<T>,
+    removed: GSet<T>,
+}
+
+impl<T: Element> TwoPSet<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, element: T) {
+        self.added.add(element);
+    }
+
+    /// Removes `element`, if the set has it.
+    pub fn remove(&mut self, element: T) {
+        if self.added.contains(&element) {
+            self.removed.add(element);
+        }
+    }
+}
+
+impl<T: Element> Default for TwoPSet<T> {
+    fn default() -> Self {
+        Self {
+            added: GSet::new(),
+            removed: GSet::new(),
+        }
+    }
+}
+
+impl<T: Element> Crdt for TwoPSet<T> {
+    type Value = HashSet<T>;
+
+    fn merge(&mut self, other: &Self) {
+        self.added.merge(&other.added);
+        self.removed.merge(&other.removed);
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        Self {
+            added: self.added.delta(&since.added),
+            removed: self.removed.delta(&since.removed),
+        }
+    }
+
+    fn value(&self) -> HashSet<T> {
+        let (added, removed) = (&self.added.elements, &self.removed.elements);
+        added.difference(removed).cloned().collect()
+    }
+}
+
+/// Tells apart the additions of an element: the node that added it, and how
+/// many additions that node had made.
+pub type Tag = (String, u64);
+
+/// A set elements can be added to and removed from any number of times.
+/// Each addition gets a unique tag, and a removal removes the tags its
+/// replica has seen, so an addition concurrent with a removal wins.
+#[serde_as]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct ORSet<T: Element> {
+    #[serde_as(as = "Vec<(_, _)>")]
+    added: HashMap<T, HashSet<Tag>>,
+    removed: HashSet<Tag>,
+    /// The number of additions each node made.
+    seqs: HashMap<String, u64>,
+}
+
+impl<T: Element> ORSet<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, node: &str, element: T) {
+        let seq = self.seqs.entry(node.to_string()).or_default();
+        *seq += 1;
+        let tag = (node.to_string(), *seq);
+        self.added.entry(element).or_default().insert(tag);
+    }
+
+    pub fn remove(&mut self, element: &T) {
+        if let Some(tags) = self.added.get(element) {
+            self.removed.extend(tags.iter().cloned());
+        }
+    }
+}
+
+impl<T: Element> Default for ORSet<T> {
+    fn default() -> Self {
+        Self {
+            added: HashMap::new(),
+            removed: HashSet::new(),
+            seqs: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Element> Crdt for ORSet<T> {
+    type Value = HashSet<T>;
+
+    fn merge(&mut self, other: &Self) {
+        for (element, tags) in &other.added {
+            let ours = self.added.entry(element.clone()).or_default();
+            ours.extend(tags.iter().cloned());
+        }
+        self.removed.extend(other.removed.iter().cloned());
+        for (node, n) in &other.seqs {
+            let seq = self.seqs.entry(node.clone()).or_default();
+            *seq = (*seq).max(*n);
+        }
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        let mut added = HashMap::new();
+        for (element, tags) in &self.added {
+            let tags: HashSet<Tag> = match since.added.get(element) {
+                Some(seen) => tags.difference(seen).cloned().collect(),
+                None => tags.clone(),
+            };
+            if !tags.is_empty() {
+                added.insert(element.clone(), tags);
+            }
+        }
+        let seqs = self
+            .seqs
+            .iter()
+            .filter(|(node, n)| since.seqs.get(*node).is_none_or(|m| *n > m));
+        Self {
+            added,
+            removed: self.removed.difference(&since.removed).cloned().collect(),
+            seqs: seqs.map(|(node, n)| (node.clone(), *n)).collect(),
+        }
+    }
+
+    fn value(&self) -> HashSet<T> {
+        let present = self
+            .added
+            .iter()
+            .filter(|(_, tags)| tags.iter().any(|tag| !self.removed.contains(tag)));
+        present.map(|(element, _)| element.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crdt::tests::{check_laws, states};
+    use rand::Rng;
+
+    #[test]
+    fn g_set_laws() {
+        check_laws(&states(|s: &mut GSet<u8>, _, rng| {
+            s.add(rng.gen_range(0..10))
+        }));
+    }
+
+    #[test]
+    fn two_p_set_laws() {
+        check_laws(&states(|s: &mut TwoPSet<u8>, _, rng| {
+            match rng.gen_bool(0.6) {
+                true => s.add(rng.gen_range(0..10)),
+                false => s.remove(rng.gen_range(0..10)),
+            }
+        }));
+    }
+
+    #[test]
+    fn or_set_laws() {
+        check_laws(&states(|s: &mut ORSet<u8>, node, rng| {
+            match rng.gen_bool(0.6) {
+                true => s.add(node, rng.gen_range(0..10)),
+                false => s.remove(&rng.gen_range(0..10)),
+            }
+        }));
+    }
+
+    #[test]
+    fn two_p_set_removes_for_good() {
+        let mut s = TwoPSet::new();
+        s.add(1);
+        s.remove(1);
+        s.add(1);
+        assert!(s.value().is_empty());
+    }
+
+    #[test]
+    fn or_set_addition_wins_over_concurrent_removal() {
+        let (mut a, mut b) = (ORSet::new(), ORSet::new());
+        a.add("n0", 1);
+        b.merge(&a);
+        b.remove(&1);
+        a.add("n0", 1);
+        a.merge(&b);
+        b.merge(&a);
+        assert_eq!(a.value(), HashSet::from([1]));
+        assert_eq!(b.value(), HashSet::from([1]));
+    }
+}
@@ -20,12 +20,9 @@ struct Handler {}
 impl Node for Handler {
     async fn process(&self, runtime: Runtime, req: Message) -> Result<()> {
         let msg: Result<Request> = req.body.as_obj();
-        match msg {
-            Ok(Request::Echo {}) => {
-                let echo = req.body.clone().with_type("echo_ok");
-                return runtime.reply(req, echo).await;
-            }
-            _ => {}
+        if let Ok(Request::Echo {}) = msg {
+            let echo = req.body.clone().with_type("echo_ok");
+            return runtime.reply(req, echo).await;
         }
         done(runtime, req)
     }
@@ -0,0 +1,375 @@
+//! Gossip: replicating a state that only ever merges.
+//!
+//! A node updates its copy of the state locally. Every round it sends each
+//! neighbour the diff between its state and the one that neighbour is known
+//! to have merged: what the neighbour acknowledged from it and what it
+//! received from the neighbour. A diff that isn't acknowledged in time gets
+//! sent again as part of the next round's. A node merges the diffs it
+//! receives into its own state and passes them on in its next round, so
+//! every update reaches every node as long as the neighbours connect them
+//! all.
+//!
+//! Any [`State`] can be gossiped, such as any [`Crdt`](crate::crdt::Crdt).
+//! A binary hosts the engine by calling [`Gossip::start`] on init and
+//! handing it the messages its own handler doesn't know, with
+//! [`Gossip::process`]. Anything else can carry the diffs itself, as tests
+//! do, with [`Gossip::diffs`], [`Gossip::acked`] and [`Gossip::receive`].
+
+use crate::crdt::Crdt;
+use crate::hlc::Hlc;
+use maelstrom::protocol::Message;
+use maelstrom::{done, Result, Runtime};
+use rand::seq::SliceRandom;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_context::context::Context;
+
+/// How often a node sends its neighbours what they are missing, unless set
+/// otherwise.
+const ROUND: Duration = Duration::from_millis(150);
+
+/// How long to wait for a neighbour to acknowledge a diff.
+const RPC_TIMEOUT: Duration = Duration::from_millis(400);
+
+pub trait State: Clone + Default + Serialize + DeserializeOwned + Send + Sync + 'static {
+    /// Merges `other` into this state. Merging has to be commutative,
+    /// associative and idempotent.
+    fn merge(&mut self, other: &Self);
+
+    /// The part of this state `since` is missing: merging it into `since`
+    /// has the same result as merging all of this state.
+    fn diff(&self, since: &Self) -> Self;
+
+    /// Whether there is nothing to merge.
+    fn is_empty(&self) -> bool;
+}
+
+type OnMerge<S> = Arc<dyn Fn(&S) + Send + Sync>;
+
+#[derive(Clone)]
+pub struct Gossip<S> {
+    interval: Duration,
+    /// How many neighbours to send diffs to each round, picked at random, or
+    /// all of them if unset.
+    fanout: Option<usize>,
+    /// Stamped on the diffs and merged from their acks, if set.
+    clock: Option<Arc<Hlc>>,
+    on_merge: Option<OnMerge<S>>,
+    inner: Arc<Mutex<Inner<S>>>,
+}
+
+struct Inner<S> {
+    state: S,
+    /// A state each neighbour has merged, at least.
+    known: HashMap<String, S>,
+    /// Neighbours with a diff on its way, which get no other until it is
+    /// acknowledged or times out.
+    inflight: HashSet<String>,
+    /// The nodes to gossip with, or every other node if unset.
+    neighbours: Option<Vec<String>>,
+}
+
+impl<S: State> Gossip<S> {
+    pub fn new() -> Self {
+        Self {
+            interval: ROUND,
+            fanout: None,
+            clock: None,
+            on_merge: None,
+            inner: Arc::new(Mutex::new(Inner {
+                state: S::default(),
+                known: HashMap::new(),
+                inflight: HashSet::new(),
+                neighbours: None,
+            })),
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sends diffs to `fanout` random neighbours a round, rather than all.
+    pub fn with_fanout(mut self, fanout: usize) -> Self {
+        self.fanout = Some(fanout);
+        self
+    }
+
+    /// Piggybacks `clock` on the gossip messages.
+    pub fn with_clock(mut self, clock: Arc<Hlc>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Calls `f` with every diff received from another node, once it is
+    /// merged.
+    pub fn on_merge(mut self, f: impl Fn(&S) + Send + Sync + 'static) -> Self {
+        self.on_merge = Some(Arc::new(f));
+        self
+    }
+
+    /// Updates the local state with `f`, and returns what it returns.
+    pub fn update<R>(&self, f: impl FnOnce(&mut S) -> R) -> R {
+        f(&mut self.inner.lock().unwrap().state)
+    }
+
+    /// Reads the local state with `f`, and returns what it returns.
+    pub fn read<R>(&self, f: impl FnOnce(&S) -> R) -> R {
+        f(&self.inner.lock().unwrap().state)
+    }
+
+    /// Gossips with `neighbours` only, such as the ones a topology gives.
+    pub fn set_neighbours(&self, neighbours: Vec<String>) {
+        self.inner.lock().unwrap().neighbours = Some(neighbours);
+    }
+
+    /// Starts the rounds.
+    pub fn start(&self, runtime: Runtime) {
+        let gossip = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(gossip.interval).await;
+                gossip.round(&runtime);
+            }
+        });
+    }
+
+    fn round(&self, runtime: &Runtime) {
+        let others: Vec<String> = runtime.neighbours().cloned().collect();
+        for (n, diff) in self.diffs(&others) {
+            let (gossip, r0) = (self.clone(), runtime.clone());
+            tokio::spawn(async move {
+                let msg = Request::Gossip {
+                    state: diff.clone(),
+                };
+                let (ctx, _handler) = Context::with_timeout(RPC_TIMEOUT);
+                let reply = match &gossip.clock {
+                    Some(clock) => r0.call(ctx, n.clone(), clock.stamp(msg)).await,
+                    None => r0.call(ctx, n.clone(), msg).await,
+                };
+                if let (Ok(reply), Some(clock)) = (&reply, &gossip.clock) {
+                    clock.observe(reply);
+                }
+                gossip.acked(&n, &diff, reply.is_ok());
+            });
+        }
+    }
+
+    /// The diffs to send this round, to the neighbours set or else to
+    /// `others`, by neighbour. Each of those neighbours now has a diff in
+    /// flight until [`Gossip::acked`] hears how it went.
+    pub fn diffs(&self, others: &[String]) -> Vec<(String, S)> {
+        let mut inner = self.inner.lock().unwrap();
+        let neighbours = match &inner.neighbours {
+            Some(neighbours) => neighbours.clone(),
+            None => others.to_vec(),
+        };
+        // The fanout picks among the neighbours that are free.
+        let mut neighbours: Vec<String> = neighbours
+            .into_iter()
+            .filter(|n| !inner.inflight.contains(n))
+            .collect();
+        if let Some(fanout) = self.fanout {
+            neighbours.shuffle(&mut rand::thread_rng());
+            neighbours.truncate(fanout);
+        }
+        let mut diffs = vec![];
+        for n in neighbours {
+            let diff = match inner.known.get(&n) {
+                Some(known) => inner.state.diff(known),
+                None => inner.state.clone(),
+            };
+            if diff.is_empty() {
+                continue;
+            }
+            inner.inflight.insert(n.clone());
+            diffs.push((n, diff));
+        }
+        diffs
+    }
+
+    /// Records whether `node` acknowledged `diff`. One it didn't gets sent
+    /// again as part of the next round's.
+    pub fn acked(&self, node: &str, diff: &S, ok: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.inflight.remove(node);
+        if ok {
+            inner.known.entry(node.to_string()).or_default().merge(diff);
+        }
+    }
+
+    /// Merges `diff`, received from `node`.
+    pub fn receive(&self, node: &str, diff: &S) {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner.state.merge(diff);
+            let known = inner.known.entry(node.to_string()).or_default();
+            known.merge(diff);
+        }
+        if let Some(on_merge) = &self.on_merge {
+            on_merge(diff);
+        }
+    }
+
+    /// Merges a diff from another node. Any other message is not supported.
+    pub async fn process(&self, runtime: Runtime, req: Message) -> Result<()> {
+        let msg: Result<Request<S>> = req.body.as_obj();
+        let Ok(Request::Gossip { state }) = msg else {
+            return done(runtime, req);
+        };
+        // The clock moves past the sender's first, so that nothing stamped
+        // here from now on comes before the writes merged in.
+        if let Some(clock) = &self.clock {
+            clock.observe(&req);
+        }
+        self.receive(&req.src, &state);
+        match &self.clock {
+            Some(clock) => {
+                let gossip = clock.stamp(Response::GossipOk {});
+                runtime.reply(req, gossip).await
+            }
+            None => runtime.reply(req, Response::GossipOk {}).await,
+        }
+    }
+}
+
+impl<C: Crdt> Gossip<C> {
+    pub fn value(&self) -> C::Value {
+        self.read(|state| state.value())
+    }
+}
+
+impl<S: State> Default for Gossip<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum Request<S> {
+    Gossip { state: S },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum Response {
+    GossipOk {},
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crdt::GSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    type Set = GSet<u32>;
+
+    fn set(elements: &[u32]) -> Set {
+        let mut set = Set::new();
+        elements.iter().for_each(|e| set.add(*e));
+        set
+    }
+
+    fn ids(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("n{}", i)).collect()
+    }
+
+    /// Runs a round on every node in turn, delivering each diff and its ack.
+    fn round(nodes: &HashMap<String, Gossip<Set>>) {
+        for (id, gossip) in nodes {
+            for (n, diff) in gossip.diffs(&[]) {
+                nodes[&n].receive(id, &diff);
+                gossip.acked(&n, &diff, true);
+            }
+        }
+    }
+
+    #[test]
+    fn every_update_reaches_every_node_along_a_line() {
+        let ids = ids(5);
+        let nodes: HashMap<String, Gossip<Set>> = (0..ids.len())
+            .map(|i| {
+                let gossip = Gossip::<Set>::new();
+                let neighbours = [i.checked_sub(1), Some(i + 1)];
+                let neighbours = neighbours.into_iter().flatten().filter_map(|j| ids.get(j));
+                gossip.set_neighbours(neighbours.cloned().collect());
+                gossip.update(|s| s.add(i as u32));
+                (ids[i].clone(), gossip)
+            })
+            .collect();
+        for _ in 0..ids.len() {
+            round(&nodes);
+        }
+        for gossip in nodes.values() {
+            assert_eq!(gossip.read(|s| s.clone()), set(&[0, 1, 2, 3, 4]));
+        }
+        // Nothing is left to send once everyone has everything.
+        assert!(nodes.values().all(|gossip| gossip.diffs(&[]).is_empty()));
+    }
+
+    #[test]
+    fn sends_a_neighbour_only_what_it_is_missing() {
+        let (a, b) = (Gossip::<Set>::new(), Gossip::<Set>::new());
+        let others = |id: &str| vec![id.to_string()];
+        a.update(|s| s.add(1));
+        for (_, diff) in a.diffs(&others("b")) {
+            b.receive("a", &diff);
+            a.acked("b", &diff, true);
+        }
+        a.update(|s| s.add(2));
+        assert_eq!(a.diffs(&others("b")), vec![("b".to_string(), set(&[2]))]);
+        // Nor does b send back what it got from a.
+        assert!(b.diffs(&others("a")).is_empty());
+    }
+
+    #[test]
+    fn one_diff_in_flight_per_neighbour() {
+        let a = Gossip::<Set>::new();
+        let others = ["b".to_string()];
+        a.update(|s| s.add(1));
+        let diffs = a.diffs(&others);
+        assert_eq!(diffs, vec![("b".to_string(), set(&[1]))]);
+        a.update(|s| s.add(2));
+        assert!(a.diffs(&others).is_empty());
+        a.acked("b", &diffs[0].1, true);
+        assert_eq!(a.diffs(&others), vec![("b".to_string(), set(&[2]))]);
+    }
+
+    #[test]
+    fn a_diff_not_acknowledged_is_sent_again() {
+        let a = Gossip::<Set>::new();
+        let others = ["b".to_string()];
+        a.update(|s| s.add(1));
+        let diffs = a.diffs(&others);
+        a.acked("b", &diffs[0].1, false);
+        a.update(|s| s.add(2));
+        assert_eq!(a.diffs(&others), vec![("b".to_string(), set(&[1, 2]))]);
+    }
+
+    #[test]
+    fn fanout_picks_some_neighbours_a_round() {
+        let a = Gossip::<Set>::new().with_fanout(2);
+        a.update(|s| s.add(1));
+        assert_eq!(a.diffs(&ids(5)).len(), 2);
+        assert_eq!(a.diffs(&ids(5)).len(), 2);
+        assert_eq!(a.diffs(&ids(5)).len(), 1);
+    }
+
+    #[test]
+    fn on_merge_sees_each_diff_received() {
+        let merged = Arc::new(AtomicUsize::new(0));
+        let counted = merged.clone();
+        let b = Gossip::<Set>::new().on_merge(move |diff| {
+            counted.fetch_add(diff.value().len(), Ordering::Relaxed);
+        });
+        b.receive("a", &set(&[1, 2]));
+        b.receive("c", &set(&[3]));
+        assert_eq!(merged.load(Ordering::Relaxed), 3);
+        assert_eq!(b.value(), set(&[1, 2, 3]).value());
+    }
+}
@@ -0,0 +1,68 @@
+use async_trait::async_trait;
+use gossip_glomers::crdt::GSet;
+use gossip_glomers::gossip::Gossip;
+use maelstrom::protocol::Message;
+use maelstrom::{done, Node, Result, Runtime};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+pub(crate) fn main() {
+    let _ = Runtime::init(try_main());
+}
+
+async fn try_main() -> Result<()> {
+    let handler = Arc::new(Handler::new());
+    Runtime::new().with_handler(handler).run().await
+}
+
+/// A grow-only set, as a state-based CRDT: merging two replicas is their
+/// union, so every node ends up with every element added anywhere.
+#[derive(Clone)]
+struct Handler {
+    elements: Gossip<GSet<Value>>,
+}
+
+impl Handler {
+    fn new() -> Self {
+        Self {
+            elements: Gossip::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl Node for Handler {
+    async fn process(&self, runtime: Runtime, req: Message) -> Result<()> {
+        let msg: Result<Request> = req.body.as_obj();
+        match msg {
+            Ok(Request::Init {}) => {
+                self.elements.start(runtime.clone());
+            }
+            Ok(Request::Add { element }) => {
+                self.elements.update(|elements| elements.add(element));
+                return runtime.reply(req, Response::AddOk {}).await;
+            }
+            Ok(Request::Read {}) => {
+                let value = self.elements.value().into_iter().collect();
+                return runtime.reply(req, Response::ReadOk { value }).await;
+            }
+            Err(_) => return self.elements.process(runtime, req).await,
+        }
+        done(runtime, req)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum Request {
+    Init {},
+    Add { element: Value },
+    Read {},
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum Response {
+    AddOk {},
+    ReadOk { value: Vec<Value> },
+}
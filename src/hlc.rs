@@ -0,0 +1,184 @@
+//! Hybrid logical clocks.
+//!
+//! A timestamp is a wall-clock reading in milliseconds with a logical counter
+//! to order events within the same millisecond. A node's clock never goes
+//! backwards, even if its wall clock does, and it moves past every timestamp
+//! it receives, so timestamps respect causality like Lamport clocks do while
+//! staying close to real time.
+//!
+//! Nodes piggyback their clock on the messages they exchange: [`Hlc::stamp`]
+//! adds an `hlc` field to an outgoing body and [`Hlc::observe`] merges the
+//! one on an incoming message.
+
+use maelstrom::protocol::Message;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The message body field carrying the sender's clock.
+pub const FIELD: &str = "hlc";
+
+/// Milliseconds since the Unix epoch, then the logical counter.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct Timestamp(pub u64, pub u32);
+
+impl Timestamp {
+    /// The timestamp right after this one. Once the logical counter runs out
+    /// it carries over into the next millisecond, which only moves the clock
+    /// ahead of the wall clock, never back.
+    fn next(self) -> Self {
+        match self.1.checked_add(1) {
+            Some(logical) => Timestamp(self.0, logical),
+            None => Timestamp(self.0.saturating_add(1), 0),
+        }
+    }
+}
+
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}.{}", self.0, self.1)
+    }
+}
+
+pub struct Hlc {
+    last: Mutex<Timestamp>,
+    /// Reads the wall clock in milliseconds.
+    wall: fn() -> u64,
+}
+
+impl Default for Hlc {
+    fn default() -> Self {
+        Self {
+            last: Mutex::default(),
+            wall: wall_clock,
+        }
+    }
+}
+
+/// A message body with the sender's clock added to it.
+#[derive(Serialize)]
+pub struct Stamped<T> {
+    #[serde(flatten)]
+    pub body: T,
+    pub hlc: Timestamp,
+}
+
+impl Hlc {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ticks the clock for a local event, such as sending a message, and
+    /// returns its timestamp. Every call returns a later timestamp than the
+    /// one before.
+    pub fn now(&self) -> Timestamp {
+        let wall = (self.wall)();
+        let mut last = self.last.lock().unwrap();
+        *last = match wall > last.0 {
+            true => Timestamp(wall, 0),
+            false => last.next(),
+        };
+        *last
+    }
+
+    /// Ticks the clock for receiving `remote` from another node, and returns
+    /// a timestamp later than both `remote` and every one returned so far.
+    pub fn update(&self, remote: Timestamp) -> Timestamp {
+        let wall = (self.wall)();
+        let mut last = self.last.lock().unwrap();
+        let ms = wall.max(last.0).max(remote.0);
+        *last = match (ms == last.0, ms == remote.0) {
+            (true, true) => (*last).max(remote).next(),
+            (true, false) => last.next(),
+            (false, true) => remote.next(),
+            (false, false) => Timestamp(ms, 0),
+        };
+        *last
+    }
+
+    /// Adds a fresh timestamp to `body` before it gets sent.
+    pub fn stamp<T>(&self, body: T) -> Stamped<T> {
+        Stamped {
+            body,
+            hlc: self.now(),
+        }
+    }
+
+    /// Merges the timestamp `msg` carries, if any, and returns the timestamp
+    /// of receiving it.
+    pub fn observe(&self, msg: &Message) -> Option<Timestamp> {
+        let remote = msg.body.extra.get(FIELD)?;
+        let remote = serde_json::from_value(remote.clone()).ok()?;
+        Some(self.update(remote))
+    }
+}
+
+fn wall_clock() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    /// A wall clock the test sets by hand. Every test that reads it runs in
+    /// its own thread, so each gets its own.
+    fn fake_wall() -> u64 {
+        WALL.with(|wall| wall.load(Ordering::SeqCst))
+    }
+
+    thread_local! {
+        static WALL: AtomicU64 = const { AtomicU64::new(0) };
+    }
+
+    fn set_wall(ms: u64) {
+        WALL.with(|wall| wall.store(ms, Ordering::SeqCst));
+    }
+
+    fn clock(last: Timestamp) -> Hlc {
+        Hlc {
+            last: Mutex::new(last),
+            wall: fake_wall,
+        }
+    }
+
+    #[test]
+    fn readings_keep_growing_while_the_wall_clock_goes_back() {
+        let clock = clock(Timestamp::default());
+        set_wall(100);
+        assert_eq!(clock.now(), Timestamp(100, 0));
+        assert_eq!(clock.now(), Timestamp(100, 1));
+        set_wall(40);
+        assert_eq!(clock.now(), Timestamp(100, 2));
+        assert_eq!(clock.update(Timestamp(90, 7)), Timestamp(100, 3));
+        set_wall(101);
+        assert_eq!(clock.now(), Timestamp(101, 0));
+    }
+
+    #[test]
+    fn updates_move_past_the_remote_timestamp() {
+        let clock = clock(Timestamp::default());
+        set_wall(100);
+        assert_eq!(clock.update(Timestamp(200, 5)), Timestamp(200, 6));
+        assert_eq!(clock.update(Timestamp(200, 3)), Timestamp(200, 7));
+        assert_eq!(clock.update(Timestamp(200, 9)), Timestamp(200, 10));
+        assert_eq!(clock.now(), Timestamp(200, 11));
+        set_wall(300);
+        assert_eq!(clock.update(Timestamp(250, 9)), Timestamp(300, 0));
+    }
+
+    #[test]
+    fn the_logical_counter_carries_into_the_next_millisecond() {
+        set_wall(0);
+        let clock = clock(Timestamp(100, u32::MAX));
+        assert_eq!(clock.now(), Timestamp(101, 0));
+        assert_eq!(clock.update(Timestamp(101, u32::MAX)), Timestamp(102, 0));
+        assert_eq!(clock.update(Timestamp(500, u32::MAX)), Timestamp(501, 0));
+    }
+}
@@ -1,14 +1,21 @@
 use async_trait::async_trait;
 
-use maelstrom::kv::{lin_kv, Storage, KV};
+use futures::future::join_all;
+use futures::stream::{self, StreamExt, TryStreamExt};
+
+use gossip_glomers::hlc::Hlc;
+use gossip_glomers::kv::{retry, Kv, KvError, Result as KvResult};
+use maelstrom::kv::lin_kv;
 use maelstrom::protocol::Message;
 use maelstrom::{done, Error, Node, Result, Runtime};
 use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use tokio_context::context::Context;
+use tokio::sync::{Mutex as AsyncMutex, OnceCell};
 
 pub(crate) fn main() {
   let _ = Runtime::init(try_main());
@@ -22,17 +29,434 @@ async fn try_main() -> Result<()> {
 
 #[derive(Clone)]
 struct Handler {
-  s: Storage,
+  s: Kv,
+  /// Names batches. Its readings only grow, across restarts too, as long as
+  /// the wall clock does.
+  clock: Arc<Hlc>,
   inner: Arc<Mutex<State>>,
 }
 
 impl Handler {
   fn new(runtime: Runtime) -> Self {
     Self {
-      s: lin_kv(runtime),
-      inner: Arc::new(Mutex::new(State {
-        logs: HashMap::new(),
-      })),
+      s: Kv::new(lin_kv(runtime)),
+      clock: Arc::new(Hlc::new()),
+      inner: Arc::new(Mutex::new(State::default())),
+    }
+  }
+
+  fn lease(&self, key: &str) -> Arc<AsyncMutex<Option<Lease>>> {
+    let mut inner = self.inner.lock().unwrap();
+    inner.leases.entry(key.to_string()).or_default().clone()
+  }
+
+  /// Takes the next offset of `key` from this node's lease, claiming a new
+  /// lease first if there is none left or it expired, and stores `entry`
+  /// under it. If a poll gave up on the offset first, tries the next one.
+  async fn append(&self, node: &str, key: &str, entry: Entry) -> Result<usize> {
+    loop {
+      let offset = {
+        let lease = self.lease(key);
+        let mut lease = lease.lock().await;
+        let mut current = match lease.take() {
+          Some(l) if l.usable() => l,
+          Some(l) => {
+            self.seal(key, l).await;
+            self.claim(node, key).await?
+          }
+          None => self.claim(node, key).await?,
+        };
+        let offset = current.take();
+        *lease = Some(current);
+        offset
+      };
+
+      if self.place(key, offset, &entry).await? {
+        return Ok(offset);
+      }
+    }
+  }
+
+  /// Stores `entry` under `offset` of `key` unless something is there
+  /// already, and returns whether `entry` is. Offsets are only ever written
+  /// once: either by the node that leased them, or with a skip by a poll
+  /// that gave up waiting for it.
+  async fn place(&self, key: &str, offset: usize, entry: &Entry) -> KvResult<bool> {
+    let entry_key = format!("{}-{}", key, offset);
+    Ok(self.settle(&entry_key, None, entry).await? == *entry)
+  }
+
+  /// Moves `key` from `from` to `to`, or creates it holding `to` if `from` is
+  /// `None`, and returns what it holds afterwards: `to`, or whatever got there
+  /// first. A cas that timed out is tried again once a read shows it didn't
+  /// go through; writing `to` over `to` would change nothing anyway.
+  async fn settle<T>(&self, key: &str, from: Option<&T>, to: &T) -> KvResult<T>
+  where
+    T: Clone + PartialEq + Serialize + Deserialize<'static> + Send + Sync,
+  {
+    retry(|| async {
+      let moved = match from {
+        Some(from) => self.s.cas(key.to_string(), from.clone(), to.clone(), false),
+        None => self.s.cas(key.to_string(), to.clone(), to.clone(), true),
+      };
+      match moved.await {
+        Ok(()) => Ok(to.clone()),
+        Err(KvError::PreconditionFailed) | Err(KvError::Indefinite) => {
+          match self.s.get::<T>(key.to_string()).await {
+            Ok(current) if Some(&current) != from => Ok(current),
+            Ok(_) | Err(KvError::KeyDoesNotExist) => Err(KvError::Timeout),
+            Err(e) => Err(e),
+          }
+        }
+        Err(e) => Err(e),
+      }
+    })
+    .await
+  }
+
+  /// Appends `msg` to `key` as sequence number `seq` of `producer`, unless an
+  /// earlier attempt of it got an offset already, which is returned instead.
+  /// Retries may reach another node, or this one after a restart, so the
+  /// offset that counts is recorded in the producer's window in lin-kv.
+  /// Attempts racing each other can both append, but only the entry the
+  /// window names turns into a message; the others turn into skips.
+  async fn send_once(
+    &self,
+    node: &str,
+    key: &str,
+    msg: usize,
+    producer: String,
+    seq: u64,
+  ) -> Result<usize> {
+    let window_key = format!("producer-{}", producer);
+    let window: Window = self.s.get_or(window_key, Window::default()).await?;
+    let sent = match window.check(key, seq)? {
+      Some(offset) => Sent {
+        key: key.to_string(),
+        offset,
+      },
+      None => {
+        let entry = Entry::Produced {
+          msg,
+          producer: producer.clone(),
+          seq,
+        };
+        let offset = self.append(node, key, entry).await?;
+        let ours = Sent {
+          key: key.to_string(),
+          offset,
+        };
+        let sent = self.decide(&producer, seq, ours.clone()).await?;
+        if sent.as_ref() != Some(&ours) {
+          self.finalize(key, offset, false).await?;
+        }
+        match sent {
+          Some(sent) if sent.key == key => sent,
+          // The producer used the sequence number for another key before, or
+          // sent so much since that it fell out of the window.
+          _ => return Err(Box::new(Error::PreconditionFailed)),
+        }
+      }
+    };
+    match self.finalize(key, sent.offset, true).await? {
+      Entry::Msg(_) => Ok(sent.offset),
+      // A poll found the send out of the window before it was finalized.
+      _ => Err(Box::new(Error::PreconditionFailed)),
+    }
+  }
+
+  /// Records `ours` as the attempt of `producer`'s send `seq` that counts,
+  /// unless another one was recorded first, and returns the one that counts.
+  /// Returns `None` if `seq` has fallen out of the window.
+  async fn decide(&self, producer: &str, seq: u64, ours: Sent) -> KvResult<Option<Sent>> {
+    let window_key = format!("producer-{}", producer);
+    retry(|| async {
+      let window: Window = self.s.get_or(window_key.clone(), Window::default()).await?;
+      if seq < window.low {
+        return Ok(None);
+      }
+      if let Some(sent) = window.sent.get(&seq) {
+        return Ok(Some(sent.clone()));
+      }
+      let mut next = window.clone();
+      next.record(seq, ours.clone());
+      // The next read tells whether a cas we didn't hear back from went
+      // through.
+      let recorded = self.s.cas(window_key.clone(), window, next, true).await;
+      recorded.map_err(KvError::reread)?;
+      Ok(Some(ours.clone()))
+    })
+    .await
+  }
+
+  /// Turns the entry at `offset` of `key`, if it is still a send's, into its
+  /// message if `counts`, or into a skip if not, so polls stop looking the
+  /// send up. Returns what the entry holds afterwards.
+  async fn finalize(&self, key: &str, offset: usize, counts: bool) -> KvResult<Entry> {
+    let entry_key = format!("{}-{}", key, offset);
+    let entry: Entry = self.s.get(entry_key.clone()).await?;
+    match &entry {
+      Entry::Produced { msg, .. } => {
+        let to = match counts {
+          true => Entry::Msg(*msg),
+          false => Entry::Skipped {},
+        };
+        self.settle(&entry_key, Some(&entry), &to).await
+      }
+      _ => Ok(entry),
+    }
+  }
+
+  /// Claims the lowest unclaimed block of `LEASE_SIZE` offsets of `key`.
+  /// Blocks are claimed in order, so a claimed block is never preceded by an
+  /// unclaimed one for longer than it takes someone to claim it.
+  async fn claim(&self, node: &str, key: &str) -> Result<Lease> {
+    let hint_key = format!("{}-lease", key);
+    let mine = LeaseRecord {
+      owner: node.to_string(),
+      claimed: self.clock.now().0,
+      fill: None,
+    };
+
+    let mut block: usize = self.s.get_or(hint_key.clone(), 0).await?;
+    loop {
+      let record_key = format!("{}-lease-{}", key, block);
+      let claimed = self
+        .s
+        .cas(
+          record_key.clone(),
+          LeaseRecord::default(),
+          mine.clone(),
+          true,
+        )
+        .await;
+      match claimed {
+        Ok(()) => break,
+        // Either someone else has the block, or an earlier attempt of ours
+        // went through without us hearing back; the record tells which.
+        Err(KvError::PreconditionFailed) | Err(KvError::Indefinite) => {
+          match self.s.get::<LeaseRecord>(record_key).await {
+            Ok(record) if record == mine => break,
+            Ok(_) => block += 1,
+            Err(KvError::KeyDoesNotExist) => {}
+            Err(e) => return Err(e.into()),
+          }
+        }
+        Err(e) => return Err(e.into()),
+      }
+    }
+
+    // The hint only saves the next claim a few lookups, so losing the race to
+    // move it forward is fine.
+    let _ = retry(|| async {
+      let hint: usize = self.s.get_or(hint_key.clone(), 0).await?;
+      if hint > block {
+        return Ok(());
+      }
+      let moved = self.s.cas(hint_key.clone(), hint, block + 1, true).await;
+      moved.map_err(KvError::reread)
+    })
+    .await;
+
+    Ok(Lease {
+      block,
+      next: block * LEASE_SIZE,
+      end: (block + 1) * LEASE_SIZE,
+      record: mine,
+      claimed: Instant::now(),
+      used: Instant::now(),
+    })
+  }
+
+  /// Gives up the leases no send has drawn from for `LEASE_IDLE`, and the
+  /// ones that expired.
+  async fn seal_idle(&self) {
+    let leases: Vec<(String, Arc<AsyncMutex<Option<Lease>>>)> = {
+      let inner = self.inner.lock().unwrap();
+      let leases = inner.leases.iter();
+      leases.map(|(k, l)| (k.clone(), l.clone())).collect()
+    };
+
+    for (key, lease) in leases {
+      let mut lease = lease.lock().await;
+      match lease.take() {
+        Some(l) if l.idle() => self.seal(&key, l).await,
+        other => *lease = other,
+      }
+    }
+  }
+
+  /// Records how far `lease` got, so polls can skip the offsets left unused.
+  /// The lease is gone even if that fails: the record may have been written
+  /// all the same, and a poll may have skipped past it. If the record isn't
+  /// there, polls wait out the lease going stale instead.
+  async fn seal(&self, key: &str, lease: Lease) {
+    // Exhausted leases have no gap to report.
+    if lease.next == lease.end {
+      return;
+    }
+    let record = LeaseRecord {
+      fill: Some(lease.next),
+      ..lease.record
+    };
+    let record_key = format!("{}-lease-{}", key, lease.block);
+    let _ = self.s.put(record_key, record).await;
+  }
+
+  /// Reads the visible messages of `key` from `offset` on. Reads are issued
+  /// a window at a time, the window doubling while every offset in it turns
+  /// out to hold a message, and are consumed in offset order so the first
+  /// offset that has to wait ends the poll. The batches met along the way
+  /// are looked up in `batches`, which the other keys of the same poll
+  /// share.
+  async fn poll(&self, key: &str, offset: usize, batches: &Batches) -> Result<Vec<Log>> {
+    let mut logs: Vec<Log> = vec![];
+    let mut idx = offset;
+    let mut window = 1;
+    loop {
+      let start = idx;
+      let entries =
+        join_all((start..start + window).map(|i| self.s.get::<Entry>(format!("{}-{}", key, i))))
+          .await;
+
+      for (i, entry) in (start..).zip(entries) {
+        if i < idx {
+          continue;
+        }
+        let entry = match entry {
+          Ok(entry) => entry,
+          // Unless the offset was never handed out, its message isn't
+          // written yet and anything after it has to wait so polls never
+          // skip it.
+          Err(KvError::KeyDoesNotExist) => match self.skip_gap(key, i).await? {
+            Some(next) => {
+              idx = next;
+              continue;
+            }
+            None => return Ok(logs),
+          },
+          Err(e) => return Err(e.into()),
+        };
+        match entry {
+          Entry::Msg(v) => logs.push(Log(i, v)),
+          Entry::Skipped {} => {}
+          Entry::Produced { producer, seq, .. } => {
+            // The sender hasn't finalized the entry yet, and never will if
+            // it died, so the first entry of the send a poll comes across
+            // counts. Sends that fell out of the window were never
+            // acknowledged, nor returned by a poll, so they are dropped.
+            let ours = Sent {
+              key: key.to_string(),
+              offset: i,
+            };
+            let counts = self.decide(&producer, seq, ours.clone()).await? == Some(ours);
+            if let Entry::Msg(msg) = self.finalize(key, i, counts).await? {
+              logs.push(Log(i, msg));
+            }
+          }
+          Entry::Batched { msg, batch } => {
+            let status = {
+              let mut batches = batches.lock().unwrap();
+              batches.entry(batch.clone()).or_default().clone()
+            };
+            let status = status.get_or_try_init(|| self.batch_status(&batch));
+            match status.await? {
+              BatchStatus::Committed => logs.push(Log(i, msg)),
+              BatchStatus::Aborted => {}
+              BatchStatus::Pending { .. } => return Ok(logs),
+            }
+          }
+        }
+        idx = i + 1;
+      }
+      window = (window * 2).min(POLL_WINDOW);
+    }
+  }
+
+  /// Where a poll can carry on from when `offset` holds no message: the start
+  /// of the next block if `offset` is in the unused tail of a sealed lease,
+  /// the next offset if the lease went stale without its message turning up,
+  /// or `None` if a message may still turn up there.
+  async fn skip_gap(&self, key: &str, offset: usize) -> Result<Option<usize>> {
+    let block = offset / LEASE_SIZE;
+    let record_key = format!("{}-lease-{}", key, block);
+    let record = match self.s.get::<LeaseRecord>(record_key).await {
+      Ok(record) => record,
+      Err(KvError::KeyDoesNotExist) => return Ok(None),
+      Err(e) => return Err(e.into()),
+    };
+    match record.gap(offset, self.clock.now().0) {
+      Gap::Unused => Ok(Some((block + 1) * LEASE_SIZE)),
+      Gap::Pending => Ok(None),
+      // A message that turns up later finds the offset taken and goes
+      // elsewhere.
+      Gap::Stale => match self.place(key, offset, &Entry::Skipped {}).await? {
+        true => Ok(Some(offset + 1)),
+        false => Ok(None),
+      },
+    }
+  }
+
+  /// Appends every message of `msgs` as part of `batch`. The batch starts out
+  /// pending, so polls stop in front of its entries, and only becomes visible
+  /// once all of them are written. If any append fails the batch is aborted
+  /// and polls skip the offsets it already took. A batch left pending for
+  /// `BATCH_STALE`, such as one whose node died, is aborted by the next poll
+  /// that comes across it, and then fails here too.
+  async fn append_batch(
+    &self,
+    node: &str,
+    batch: String,
+    msgs: HashMap<String, Vec<usize>>,
+  ) -> Result<HashMap<String, Vec<usize>>> {
+    let status_key = format!("batch-{}", batch);
+    let pending = BatchStatus::Pending {
+      started: self.clock.now().0,
+    };
+    self.settle(&status_key, None, &pending).await?;
+
+    let mut offsets = HashMap::<String, Vec<usize>>::new();
+    let mut result: Result<()> = Ok(());
+    'keys: for (key, msgs) in msgs {
+      for msg in msgs {
+        let entry = Entry::Batched {
+          msg,
+          batch: batch.clone(),
+        };
+        match self.append(node, &key, entry).await {
+          Ok(offset) => offsets.entry(key.clone()).or_default().push(offset),
+          Err(e) => {
+            result = Err(e);
+            break 'keys;
+          }
+        }
+      }
+    }
+
+    let status = match result {
+      Ok(()) => BatchStatus::Committed,
+      Err(_) => BatchStatus::Aborted,
+    };
+    match self.settle(&status_key, Some(&pending), &status).await? {
+      BatchStatus::Committed => result.map(|()| offsets),
+      // A poll gave up on the batch before it was done.
+      _ => result.and(Err(Box::new(Error::Abort))),
+    }
+  }
+
+  /// Looks up whether `batch` is visible yet. A batch still pending
+  /// `BATCH_STALE` after it started is aborted, unless its node decides it
+  /// first.
+  async fn batch_status(&self, batch: &str) -> Result<BatchStatus> {
+    let status_key = format!("batch-{}", batch);
+    let status: BatchStatus = self.s.get(status_key.clone()).await?;
+    match status {
+      BatchStatus::Pending { .. } if status.stale(self.clock.now().0) => Ok(
+        self
+          .settle(&status_key, Some(&status), &BatchStatus::Aborted)
+          .await?,
+      ),
+      status => Ok(status),
     }
   }
 }
@@ -42,34 +466,32 @@ impl Node for Handler {
   async fn process(&self, runtime: Runtime, req: Message) -> Result<()> {
     let msg: Result<Request> = req.body.as_obj();
     match msg {
-      Ok(Request::Send { key, msg }) => {
-        let mut offset: usize;
-        loop {
-          let offset_key = format!("{:}-offset", key);
-          let (ctx, _handler) = Context::new();
-          offset = self
-            .s
-            .get(ctx, offset_key.clone())
-            .await
-            .unwrap_or_else(|_| 0);
-
-          let (ctx, _handler) = Context::new();
-          let result: Result<()> = self
-            .s
-            .cas(ctx, offset_key.clone(), offset, offset + 1, true)
-            .await;
-
-          match result {
-            Ok(()) => break,
-            Err(e) => assert_eq!(
-              *e.downcast_ref::<Error>().unwrap(),
-              Error::PreconditionFailed
-            ),
-          };
-        }
-
-        let (ctx, _handler) = Context::new();
-        let _ = self.s.put(ctx, format!("{}-{}", key, offset), msg).await;
+      Ok(Request::Init {}) => {
+        let h0 = self.clone();
+        tokio::spawn(async move {
+          loop {
+            tokio::time::sleep(LEASE_IDLE / 2).await;
+            h0.seal_idle().await;
+          }
+        });
+      }
+      Ok(Request::Send {
+        key,
+        msg,
+        producer,
+        seq,
+      }) => {
+        let offset = match (producer, seq) {
+          (Some(producer), Some(seq)) => {
+            let node = runtime.node_id();
+            self.send_once(node, &key, msg, producer, seq).await?
+          }
+          _ => {
+            self
+              .append(runtime.node_id(), &key, Entry::Msg(msg))
+              .await?
+          }
+        };
 
         {
           let mut inner = self.inner.lock().unwrap();
@@ -79,64 +501,50 @@ impl Node for Handler {
         let resp = Response::SendOk { offset };
         return runtime.reply(req, resp).await;
       }
-      Ok(Request::Poll { offsets }) => {
-        let mut msgs = HashMap::<String, Vec<Log>>::new();
+      Ok(Request::SendBatch { msgs }) => {
+        let batch = format!("{}-{}", runtime.node_id(), self.clock.now());
+        let offsets = self.append_batch(runtime.node_id(), batch, msgs).await?;
 
-        for (key, offset) in offsets {
-          let (ctx, _handler) = Context::new();
-
-          let last_offset = self
-            .s
-            .get(ctx, format!("{}-offset", key))
-            .await
-            .unwrap_or_else(|_| 0);
-
-          if last_offset == 0 {
-            continue;
-          }
-
-          let mut logs: Vec<Log> = vec![];
-          for idx in offset..last_offset {
-            let (ctx, _handler) = Context::new();
-            let v = self.s.get(ctx, format!("{}-{}", key, idx)).await.unwrap();
-            logs.push(Log(idx, v));
+        {
+          let mut inner = self.inner.lock().unwrap();
+          for (key, offsets) in &offsets {
+            if let Some(offset) = offsets.last() {
+              inner.insert(key.clone(), *offset);
+            }
           }
-          msgs.insert(key, logs);
         }
 
+        let resp = Response::SendBatchOk { offsets };
+        return runtime.reply(req, resp).await;
+      }
+      Ok(Request::Poll { offsets }) => {
+        let batches = &Batches::default();
+        let msgs = stream::iter(offsets)
+          .map(|(key, offset)| async move {
+            let logs = self.poll(&key, offset, batches).await;
+            logs.map(|logs| (key, logs))
+          })
+          .buffer_unordered(POLL_KEYS)
+          .try_collect::<HashMap<String, Vec<Log>>>()
+          .await?;
+
         let resp = Response::PollOk { msgs };
         return runtime.reply(req, resp).await;
       }
       Ok(Request::CommitOffsets { offsets }) => {
         for (key, offset) in offsets {
-          loop {
-            let commit_key = format!("{}-commit", key);
-            let (ctx, _handler) = Context::new();
-
-            let commit = self
-              .s
-              .get(ctx, commit_key.clone())
-              .await
-              .unwrap_or_else(|_| 0);
-
+          let commit_key = format!("{}-commit", key);
+          retry(|| async {
+            let commit = self.s.get_or(commit_key.clone(), 0).await?;
             if commit >= offset {
-              break;
+              return Ok(());
             }
-
-            let (ctx, _handler) = Context::new();
-            let result: Result<()> = self
-              .s
-              .cas(ctx, commit_key.clone(), commit, offset, true)
-              .await;
-
-            match result {
-              Ok(()) => break,
-              Err(e) => assert_eq!(
-                *e.downcast_ref::<Error>().unwrap(),
-                Error::PreconditionFailed
-              ),
-            };
-          }
+            // Commits only move forward, so the next read tells whether a
+            // cas we didn't hear back from went through.
+            let moved = self.s.cas(commit_key.clone(), commit, offset, true).await;
+            moved.map_err(KvError::reread)
+          })
+          .await?;
         }
 
         let resp = Response::CommitOffsetsOk {};
@@ -146,13 +554,7 @@ impl Node for Handler {
         let mut offsets = HashMap::<String, usize>::new();
         for key in keys {
           let commit_key = format!("{:}-commit", key);
-          let (ctx, _handler) = Context::new();
-
-          let commit = self
-            .s
-            .get(ctx, commit_key.clone())
-            .await
-            .unwrap_or_else(|_| 0);
+          let commit = self.s.get_or(commit_key, 0).await?;
           if commit != 0 {
             offsets.insert(key, commit);
           }
@@ -169,6 +571,7 @@ impl Node for Handler {
 #[derive(Default)]
 struct State {
   logs: HashMap<String, usize>,
+  leases: HashMap<String, Arc<AsyncMutex<Option<Lease>>>>,
 }
 
 impl State {
@@ -177,23 +580,381 @@ impl State {
   }
 }
 
+/// Most lin-kv reads a poll has in flight for one key.
+const POLL_WINDOW: usize = 32;
+
+/// Most keys a poll reads at the same time.
+const POLL_KEYS: usize = 8;
+
+/// Number of consecutive offsets a node claims for a key at a time.
+const LEASE_SIZE: usize = 64;
+
+/// How long a lease may go unused before its node gives up the rest of it.
+const LEASE_IDLE: Duration = Duration::from_millis(100);
+
+/// How long a node hands out offsets from a lease, however busy it is.
+const LEASE_TTL: Duration = Duration::from_millis(1000);
+
+/// How long after a lease was claimed polls stop waiting for the messages
+/// missing from it, such as those of a node that died holding it. Well past
+/// `LEASE_TTL`, so the holder has stopped handing out offsets and has most
+/// likely written the ones it did.
+const LEASE_STALE: Duration = Duration::from_millis(3000);
+
+/// How long after a batch started polls stop waiting for it to be decided.
+/// Well past the time it takes to append a batch, unless its node died.
+const BATCH_STALE: Duration = Duration::from_millis(5000);
+
+/// Number of sends per producer whose offsets are remembered, so retries of
+/// them get the same offset. Every send with a sequence number reads and
+/// rewrites the whole window.
+const DEDUP_WINDOW: usize = 256;
+
+/// The part of a lease this node hasn't handed out yet.
+struct Lease {
+  block: usize,
+  next: usize,
+  end: usize,
+  record: LeaseRecord,
+  claimed: Instant,
+  used: Instant,
+}
+
+impl Lease {
+  /// Whether offsets may still be handed out from the lease.
+  fn usable(&self) -> bool {
+    self.next < self.end && self.claimed.elapsed() < LEASE_TTL
+  }
+
+  /// Whether the lease should be given up: no send drew from it for
+  /// `LEASE_IDLE`, or it expired.
+  fn idle(&self) -> bool {
+    self.used.elapsed() >= LEASE_IDLE || self.claimed.elapsed() >= LEASE_TTL
+  }
+
+  /// Hands out the next offset. Only for a usable lease.
+  fn take(&mut self) -> usize {
+    let offset = self.next;
+    self.next += 1;
+    self.used = Instant::now();
+    offset
+  }
+}
+
+/// What lin-kv holds under `"{key}-lease-{block}"`: which node claimed the
+/// block and when, by its clock, and, once it gave the block up, the first
+/// offset it didn't use.
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct LeaseRecord {
+  owner: String,
+  claimed: u64,
+  fill: Option<usize>,
+}
+
+impl LeaseRecord {
+  /// Why `offset` of the record's block holds no message, at `now` by this
+  /// node's clock.
+  fn gap(&self, offset: usize, now: u64) -> Gap {
+    if matches!(self.fill, Some(fill) if offset >= fill) {
+      return Gap::Unused;
+    }
+    // The lease holder stopped handing out offsets long ago, so the message
+    // is lost or on its way still. Skipping the offset settles which.
+    match now.saturating_sub(self.claimed) < LEASE_STALE.as_millis() as u64 {
+      true => Gap::Pending,
+      false => Gap::Stale,
+    }
+  }
+}
+
+/// Why an offset of a claimed block holds no message.
+#[derive(Debug, PartialEq, Eq)]
+enum Gap {
+  /// The lease was given up before the offset was handed out, and so was
+  /// the rest of the block.
+  Unused,
+  /// The message may still turn up.
+  Pending,
+  /// The lease went stale without the message turning up.
+  Stale,
+}
+
 #[derive(Clone, Copy, Serialize, Deserialize)]
 struct Log(usize, usize);
 
+/// What lin-kv holds under `"{key}-{offset}"`. Messages appended by
+/// `send_batch` also name their batch, whose status decides whether a poll
+/// may return them yet. Messages sent with a sequence number name it, and
+/// the producer's window decides whether the entry counts, until it is
+/// finalized into a message or a skip. A poll that gave up waiting for an
+/// offset's message leaves a skip there.
+///
+/// Entries are told apart by their fields alone, and the first variant that
+/// fits wins. `Skipped {}` fits any object, so it has to stay last.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Entry {
+  Msg(usize),
+  Batched {
+    msg: usize,
+    batch: String,
+  },
+  Produced {
+    msg: usize,
+    producer: String,
+    seq: u64,
+  },
+  Skipped {},
+}
+
+/// What lin-kv holds under `"producer-{producer}"`: where the attempts that
+/// count of the producer's last `DEDUP_WINDOW` sends put their messages.
+/// Sequence numbers below `low` have fallen out of the window.
+#[serde_as]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Window {
+  low: u64,
+  #[serde_as(as = "Vec<(_, _)>")]
+  sent: BTreeMap<u64, Sent>,
+}
+
+impl Window {
+  /// Returns the offset an earlier attempt of `seq` got, if any. Fails if
+  /// `seq` went with another key or fell out of the window, since whether
+  /// it was sent already can't be told any more.
+  fn check(&self, key: &str, seq: u64) -> Result<Option<usize>> {
+    if seq < self.low {
+      return Err(Box::new(Error::PreconditionFailed));
+    }
+    match self.sent.get(&seq) {
+      Some(sent) if sent.key == key => Ok(Some(sent.offset)),
+      Some(_) => Err(Box::new(Error::PreconditionFailed)),
+      None => Ok(None),
+    }
+  }
+
+  /// Records `sent` for `seq`, dropping the oldest sends past `DEDUP_WINDOW`.
+  fn record(&mut self, seq: u64, sent: Sent) {
+    self.sent.insert(seq, sent);
+    while self.sent.len() > DEDUP_WINDOW {
+      if let Some((oldest, _)) = self.sent.pop_first() {
+        self.low = oldest + 1;
+      }
+    }
+  }
+}
+
+/// Where the attempt of a send that counts put its message.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Sent {
+  key: String,
+  offset: usize,
+}
+
+/// The status of every batch a poll came across, each looked up once, so
+/// that the keys of one poll never disagree on whether a batch is visible.
+type Batches = Mutex<HashMap<String, Arc<OnceCell<BatchStatus>>>>;
+
+/// What lin-kv holds under `"batch-{batch}"`. A pending batch names when it
+/// started, by its node's clock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum BatchStatus {
+  Pending { started: u64 },
+  Committed,
+  Aborted,
+}
+
+impl BatchStatus {
+  /// Whether the batch has been pending too long, at `now` by this node's
+  /// clock, for polls to keep waiting on it.
+  fn stale(&self, now: u64) -> bool {
+    match self {
+      BatchStatus::Pending { started } => {
+        now.saturating_sub(*started) >= BATCH_STALE.as_millis() as u64
+      }
+      _ => false,
+    }
+  }
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "snake_case", tag = "type")]
 enum Request {
-  Send { key: String, msg: usize },
-  Poll { offsets: HashMap<String, usize> },
-  CommitOffsets { offsets: HashMap<String, usize> },
-  ListCommittedOffsets { keys: Vec<String> },
+  Init {},
+  Send {
+    key: String,
+    msg: usize,
+    #[serde(default)]
+    producer: Option<String>,
+    #[serde(default)]
+    seq: Option<u64>,
+  },
+  SendBatch {
+    msgs: HashMap<String, Vec<usize>>,
+  },
+  Poll {
+    offsets: HashMap<String, usize>,
+  },
+  CommitOffsets {
+    offsets: HashMap<String, usize>,
+  },
+  ListCommittedOffsets {
+    keys: Vec<String>,
+  },
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "snake_case", tag = "type")]
+#[allow(clippy::enum_variant_names)]
 enum Response {
-  SendOk { offset: usize },
-  PollOk { msgs: HashMap<String, Vec<Log>> },
+  SendOk {
+    offset: usize,
+  },
+  SendBatchOk {
+    offsets: HashMap<String, Vec<usize>>,
+  },
+  PollOk {
+    msgs: HashMap<String, Vec<Log>>,
+  },
   CommitOffsetsOk {},
-  ListCommittedOffsetsOk { offsets: HashMap<String, usize> },
+  ListCommittedOffsetsOk {
+    offsets: HashMap<String, usize>,
+  },
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn lease(next: usize, claimed: Duration, used: Duration) -> Lease {
+    let now = Instant::now();
+    Lease {
+      block: 0,
+      next,
+      end: LEASE_SIZE,
+      record: LeaseRecord::default(),
+      claimed: now.checked_sub(claimed).unwrap(),
+      used: now.checked_sub(used).unwrap(),
+    }
+  }
+
+  fn sent(key: &str, offset: usize) -> Sent {
+    Sent {
+      key: key.to_string(),
+      offset,
+    }
+  }
+
+  #[test]
+  fn leases_hand_out_their_offsets_until_exhausted_or_expired() {
+    let mut fresh = lease(LEASE_SIZE - 2, Duration::ZERO, Duration::ZERO);
+    assert!(fresh.usable());
+    assert_eq!(fresh.take(), LEASE_SIZE - 2);
+    assert_eq!(fresh.take(), LEASE_SIZE - 1);
+    assert!(!fresh.usable());
+
+    let expired = lease(0, LEASE_TTL, Duration::ZERO);
+    assert!(!expired.usable());
+    assert!(expired.idle());
+  }
+
+  #[test]
+  fn leases_nobody_draws_from_are_given_up() {
+    let mut unused = lease(0, Duration::ZERO, LEASE_IDLE);
+    assert!(unused.idle());
+    unused.take();
+    assert!(!unused.idle());
+  }
+
+  #[test]
+  fn gaps_are_skipped_past_the_fill_or_once_the_lease_is_stale() {
+    let stale = LEASE_STALE.as_millis() as u64;
+    let sealed = LeaseRecord {
+      owner: "n0".to_string(),
+      claimed: 1000,
+      fill: Some(10),
+    };
+    assert_eq!(sealed.gap(10, 1000), Gap::Unused);
+    assert_eq!(sealed.gap(LEASE_SIZE - 1, 1000), Gap::Unused);
+    assert_eq!(sealed.gap(9, 1000 + stale - 1), Gap::Pending);
+    assert_eq!(sealed.gap(9, 1000 + stale), Gap::Stale);
+
+    let held = LeaseRecord {
+      fill: None,
+      ..sealed
+    };
+    assert_eq!(held.gap(10, 1000), Gap::Pending);
+    assert_eq!(held.gap(10, 1000 + stale), Gap::Stale);
+    // A clock behind the holder's doesn't make the lease look stale.
+    assert_eq!(held.gap(10, 0), Gap::Pending);
+  }
+
+  #[test]
+  fn entries_decode_as_the_variant_they_were_written_as() {
+    let entries = [
+      Entry::Msg(3),
+      Entry::Batched {
+        msg: 3,
+        batch: "n0-1".to_string(),
+      },
+      Entry::Produced {
+        msg: 3,
+        producer: "p".to_string(),
+        seq: 7,
+      },
+      Entry::Skipped {},
+    ];
+    for entry in entries {
+      let json = serde_json::to_value(&entry).unwrap();
+      assert_eq!(serde_json::from_value::<Entry>(json).unwrap(), entry);
+    }
+  }
+
+  #[test]
+  fn objects_no_other_entry_fits_decode_as_skips() {
+    let odd = json!({ "msg": 3, "seq": 7 });
+    assert_eq!(
+      serde_json::from_value::<Entry>(odd).unwrap(),
+      Entry::Skipped {}
+    );
+    assert!(serde_json::from_value::<Entry>(json!("3")).is_err());
+  }
+
+  #[test]
+  fn windows_remember_the_last_sends_and_refuse_older_ones() {
+    let mut window = Window::default();
+    assert_eq!(window.check("k", 0).unwrap(), None);
+    for seq in 0..DEDUP_WINDOW as u64 + 2 {
+      window.record(seq, sent("k", seq as usize));
+    }
+    assert_eq!(window.low, 2);
+    assert_eq!(window.sent.len(), DEDUP_WINDOW);
+    assert!(window.check("k", 1).is_err());
+    assert_eq!(window.check("k", 2).unwrap(), Some(2));
+    // The sequence number went with another key.
+    assert!(window.check("j", 2).is_err());
+    let next = DEDUP_WINDOW as u64 + 2;
+    assert_eq!(window.check("k", next).unwrap(), None);
+  }
+
+  #[test]
+  fn windows_survive_a_round_trip_through_json() {
+    let mut window = Window::default();
+    window.record(5, sent("k", 12));
+    let json = serde_json::to_value(&window).unwrap();
+    assert_eq!(serde_json::from_value::<Window>(json).unwrap(), window);
+  }
+
+  #[test]
+  fn only_pending_batches_go_stale() {
+    let stale = BATCH_STALE.as_millis() as u64;
+    let pending = BatchStatus::Pending { started: 1000 };
+    assert!(!pending.stale(1000 + stale - 1));
+    assert!(pending.stale(1000 + stale));
+    assert!(!pending.stale(0));
+    assert!(!BatchStatus::Committed.stale(u64::MAX));
+    assert!(!BatchStatus::Aborted.stale(u64::MAX));
+  }
 }
@@ -0,0 +1,272 @@
+//! Typed access to Maelstrom's KV services.
+//!
+//! Every call is bounded by a timeout and its failure is sorted into a
+//! [`KvError`], which knows both the Maelstrom error a client should see for
+//! it and whether it is worth trying again.
+
+use maelstrom::kv::{Storage, KV};
+use maelstrom::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::future::Future;
+use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_context::context::Context;
+
+/// How long a single KV call may take before it counts as timed out.
+const CALL_TIMEOUT: Duration = Duration::from_millis(500);
+
+pub type Result<T> = StdResult<T, KvError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KvError {
+    /// No answer in time to a read or a blind write, which is safe to repeat.
+    Timeout,
+    /// No answer in time to a CAS. It may or may not have happened, and
+    /// repeating it could apply it twice, so it is never retried as is.
+    Indefinite,
+    KeyDoesNotExist,
+    PreconditionFailed,
+    /// The service refused the operation without performing it.
+    Unavailable,
+    /// Anything else, such as an unexpected error code or a reply that
+    /// doesn't parse.
+    Crash(String),
+}
+
+impl KvError {
+    /// Returns how many more attempts a failure of this class deserves and
+    /// how long to wait before each of them. Lost contention on a CAS is
+    /// retried straight away, since the next attempt re-reads the value;
+    /// unresponsive services get a few spaced-out attempts; a missing key or
+    /// a crash won't go away by asking again, and a CAS that may have
+    /// happened must not be asked for again.
+    pub fn retry_policy(&self) -> (usize, Duration) {
+        match self {
+            KvError::PreconditionFailed => (100, Duration::ZERO),
+            KvError::Timeout => (3, Duration::from_millis(50)),
+            KvError::Unavailable => (5, Duration::from_millis(100)),
+            KvError::KeyDoesNotExist | KvError::Indefinite | KvError::Crash(_) => {
+                (0, Duration::ZERO)
+            }
+        }
+    }
+
+    /// Lets [`retry`] repeat an indefinite CAS like a timeout. Only for a
+    /// read-modify-write that re-reads the key on every attempt and can tell
+    /// from what it reads whether an earlier attempt went through.
+    pub fn reread(self) -> Self {
+        match self {
+            KvError::Indefinite => KvError::Timeout,
+            e => e,
+        }
+    }
+}
+
+impl Display for KvError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            KvError::Timeout => write!(f, "kv: timeout"),
+            KvError::Indefinite => write!(f, "kv: cas timed out"),
+            KvError::KeyDoesNotExist => write!(f, "kv: key does not exist"),
+            KvError::PreconditionFailed => write!(f, "kv: precondition failed"),
+            KvError::Unavailable => write!(f, "kv: temporarily unavailable"),
+            KvError::Crash(e) => write!(f, "kv: {}", e),
+        }
+    }
+}
+
+impl From<Box<dyn StdError + Send + Sync>> for KvError {
+    fn from(e: Box<dyn StdError + Send + Sync>) -> Self {
+        match e.downcast_ref::<Error>() {
+            Some(Error::Timeout) => KvError::Timeout,
+            Some(Error::KeyDoesNotExist) => KvError::KeyDoesNotExist,
+            Some(Error::PreconditionFailed) => KvError::PreconditionFailed,
+            Some(Error::TemporarilyUnavailable) => KvError::Unavailable,
+            _ => KvError::Crash(e.to_string()),
+        }
+    }
+}
+
+impl From<KvError> for Error {
+    fn from(e: KvError) -> Self {
+        match e {
+            KvError::Timeout => Error::Timeout,
+            KvError::KeyDoesNotExist => Error::KeyDoesNotExist,
+            KvError::PreconditionFailed => Error::PreconditionFailed,
+            KvError::Unavailable => Error::TemporarilyUnavailable,
+            KvError::Indefinite | KvError::Crash(_) => Error::Crash,
+        }
+    }
+}
+
+/// Lets `?` turn a `KvError` into the boxed `maelstrom::Error` a handler
+/// returns, which the runtime then sends back as an `error` reply.
+impl From<KvError> for Box<dyn StdError + Send + Sync> {
+    fn from(e: KvError) -> Self {
+        Box::new(Error::from(e))
+    }
+}
+
+/// Runs `op` until it succeeds or fails in a way its retry policy gives up
+/// on.
+pub async fn retry<T, F, Fut>(mut op: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match op().await {
+            Ok(v) => return Ok(v),
+            Err(e) => {
+                let (attempts, backoff) = e.retry_policy();
+                if attempt >= attempts {
+                    return Err(e);
+                }
+                attempt += 1;
+                if !backoff.is_zero() {
+                    tokio::time::sleep(backoff).await;
+                }
+            }
+        }
+    }
+}
+
+/// A KV service whose calls time out and fail with a [`KvError`]. Reads and
+/// blind writes are idempotent and retried here. A CAS is not: repeating it
+/// without re-reading the value would fail again if it lost, and apply it
+/// twice if it went through unheard. Callers wrap their whole
+/// read-modify-write in [`retry`], and a CAS that times out fails with
+/// [`KvError::Indefinite`].
+#[derive(Clone)]
+pub struct Kv {
+    s: Backend,
+}
+
+#[derive(Clone)]
+enum Backend {
+    Service(Storage),
+    /// Values kept in this process, as JSON.
+    Memory(Arc<Mutex<HashMap<String, Value>>>),
+}
+
+impl Kv {
+    pub fn new(s: Storage) -> Self {
+        Self {
+            s: Backend::Service(s),
+        }
+    }
+
+    /// A linearizable store in this process's memory, which needs no
+    /// Maelstrom service to talk to. Clones share the same values, so one
+    /// can stand in for lin-kv across the restart of a node in tests.
+    pub fn memory() -> Self {
+        Self {
+            s: Backend::Memory(Arc::default()),
+        }
+    }
+
+    pub async fn get<T>(&self, key: String) -> Result<T>
+    where
+        T: Deserialize<'static> + Send,
+    {
+        let s = match &self.s {
+            Backend::Service(s) => s,
+            Backend::Memory(values) => {
+                yield_now().await;
+                let value = values.lock().unwrap().get(&key).cloned();
+                let value = value.ok_or(KvError::KeyDoesNotExist)?;
+                return T::deserialize(value).map_err(|e| KvError::Crash(e.to_string()));
+            }
+        };
+        retry(|| async {
+            let (ctx, _handler) = Context::with_timeout(CALL_TIMEOUT);
+            Ok(s.get(ctx, key.clone()).await?)
+        })
+        .await
+    }
+
+    /// Like [`Kv::get`], but a key that was never written reads as `default`.
+    pub async fn get_or<T>(&self, key: String, default: T) -> Result<T>
+    where
+        T: Deserialize<'static> + Send,
+    {
+        match self.get(key).await {
+            Err(KvError::KeyDoesNotExist) => Ok(default),
+            result => result,
+        }
+    }
+
+    pub async fn put<T>(&self, key: String, val: T) -> Result<()>
+    where
+        T: Serialize + Clone + Send + Sync,
+    {
+        let s = match &self.s {
+            Backend::Service(s) => s,
+            Backend::Memory(values) => {
+                yield_now().await;
+                values.lock().unwrap().insert(key, to_value(val)?);
+                return Ok(());
+            }
+        };
+        retry(|| async {
+            let (ctx, _handler) = Context::with_timeout(CALL_TIMEOUT);
+            Ok(s.put(ctx, key.clone(), val.clone()).await?)
+        })
+        .await
+    }
+
+    pub async fn cas<T>(&self, key: String, from: T, to: T, create: bool) -> Result<()>
+    where
+        T: Serialize + Deserialize<'static> + Send,
+    {
+        let s = match &self.s {
+            Backend::Service(s) => s,
+            Backend::Memory(values) => {
+                yield_now().await;
+                let (from, to) = (to_value(from)?, to_value(to)?);
+                let mut values = values.lock().unwrap();
+                return match values.get(&key) {
+                    Some(current) if *current != from => Err(KvError::PreconditionFailed),
+                    None if !create => Err(KvError::KeyDoesNotExist),
+                    _ => {
+                        values.insert(key, to);
+                        Ok(())
+                    }
+                };
+            }
+        };
+        let (ctx, _handler) = Context::with_timeout(CALL_TIMEOUT);
+        match s.cas(ctx, key, from, to, create).await {
+            Ok(()) => Ok(()),
+            Err(e) => match KvError::from(e) {
+                KvError::Timeout => Err(KvError::Indefinite),
+                e => Err(e),
+            },
+        }
+    }
+}
+
+fn to_value<T: Serialize>(value: T) -> Result<Value> {
+    serde_json::to_value(value).map_err(|e| KvError::Crash(e.to_string()))
+}
+
+/// Lets other tasks run before an in-memory call completes, the way they
+/// would while a call to the service is under way.
+async fn yield_now() {
+    let mut yielded = false;
+    futures::future::poll_fn(|cx| {
+        if yielded {
+            return std::task::Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        std::task::Poll::Pending
+    })
+    .await
+}
@@ -0,0 +1,6 @@
+pub mod crdt;
+pub mod gossip;
+pub mod hlc;
+pub mod kv;
+pub mod snowflake;
+pub mod testing;
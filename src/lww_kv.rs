@@ -0,0 +1,126 @@
+use async_trait::async_trait;
+use gossip_glomers::crdt::LwwMap;
+use gossip_glomers::gossip::Gossip;
+use gossip_glomers::hlc::Hlc;
+use maelstrom::protocol::Message;
+use maelstrom::{done, Error, Node, Result, Runtime};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+pub(crate) fn main() {
+    let _ = Runtime::init(try_main());
+}
+
+async fn try_main() -> Result<()> {
+    let handler = Arc::new(Handler::new());
+    Runtime::new().with_handler(handler).run().await
+}
+
+/// A highly available key-value store with the interface of Maelstrom's
+/// lww-kv service. Every node answers from its own replica and replicates
+/// writes to the others in the background; concurrent writes to a key
+/// resolve to the one with the later HLC timestamp. A cas compares against
+/// the local replica only, so two nodes can both succeed on the same value.
+#[derive(Clone)]
+struct Handler {
+    clock: Arc<Hlc>,
+    kv: Gossip<LwwMap<Value, Value>>,
+}
+
+impl Handler {
+    fn new() -> Self {
+        let clock = Arc::new(Hlc::new());
+        Self {
+            clock: clock.clone(),
+            kv: Gossip::new().with_clock(clock),
+        }
+    }
+
+    /// Writes `value` to `key` if `check` accepts the current value, with a
+    /// timestamp later than the write it replaces, which may come from a
+    /// node whose clock is ahead.
+    fn write(
+        &self,
+        node_id: &str,
+        key: Value,
+        value: Value,
+        check: impl FnOnce(Option<&Value>) -> Result<()>,
+    ) -> Result<()> {
+        self.kv.update(|kv| {
+            check(kv.get(&key))?;
+            let ts = match kv.stamp(&key) {
+                Some((ts, _)) => self.clock.update(*ts),
+                None => self.clock.now(),
+            };
+            kv.set(key, value, (ts, node_id.to_string()));
+            Ok(())
+        })
+    }
+}
+
+#[async_trait]
+impl Node for Handler {
+    async fn process(&self, runtime: Runtime, req: Message) -> Result<()> {
+        let msg: Result<Request> = req.body.as_obj();
+        match msg {
+            Ok(Request::Init {}) => {
+                self.kv.start(runtime.clone());
+            }
+            Ok(Request::Read { key }) => {
+                let Some(value) = self.kv.read(|kv| kv.get(&key).cloned()) else {
+                    return Err(Box::new(Error::KeyDoesNotExist));
+                };
+                return runtime.reply(req, Response::ReadOk { value }).await;
+            }
+            Ok(Request::Write { key, value }) => {
+                self.write(runtime.node_id(), key, value, |_| Ok(()))?;
+                return runtime.reply(req, Response::WriteOk {}).await;
+            }
+            Ok(Request::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            }) => {
+                self.write(runtime.node_id(), key, to, |current| match current {
+                    Some(value) if *value == from => Ok(()),
+                    Some(_) => Err(Box::new(Error::PreconditionFailed)),
+                    None if create_if_not_exists => Ok(()),
+                    None => Err(Box::new(Error::KeyDoesNotExist)),
+                })?;
+                return runtime.reply(req, Response::CasOk {}).await;
+            }
+            Err(_) => return self.kv.process(runtime, req).await,
+        }
+        done(runtime, req)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum Request {
+    Init {},
+    Read {
+        key: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+#[allow(clippy::enum_variant_names)]
+enum Response {
+    ReadOk { value: Value },
+    WriteOk {},
+    CasOk {},
+}
@@ -1,29 +1,116 @@
 use async_trait::async_trait;
+use gossip_glomers::kv::{retry, Kv, KvError, Result as KvResult};
+use gossip_glomers::snowflake::{Parts, Snowflake};
+use maelstrom::kv::lin_kv;
 use maelstrom::protocol::Message;
-use maelstrom::{done, Node, Result, Runtime};
+use maelstrom::{done, Error, Node, Result, Runtime};
 use serde::{Deserialize, Serialize};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::env;
 use std::sync::Arc;
+use tokio::sync::{Mutex as AsyncMutex, OnceCell};
+
+/// How far ahead of the ids it hands out a node reserves counters, as a
+/// number of counters. Ids advance by 4096 counters a millisecond at most, so
+/// this is at least a second's worth of ids per lin-kv write.
+const RESERVE_AHEAD: u64 = 4096 * 1000;
+
+/// The most ids a `generate_batch` request may ask for.
+const MAX_BATCH: usize = 1000;
 
 pub(crate) fn main() {
     let _ = Runtime::init(try_main());
 }
 
 async fn try_main() -> Result<()> {
-    let handler = Arc::new(Handler::new());
-    Runtime::new().with_handler(handler).run().await
+    let runtime = Runtime::new();
+    let handler = Arc::new(Handler::new(runtime.clone())?);
+    runtime.with_handler(handler).run().await
 }
 
-#[derive(Clone, Default)]
+#[derive(Clone)]
 struct Handler {
-    pub counter: Arc<AtomicUsize>,
+    s: Kv,
+    format: Format,
+    /// Set up by the first request to generate ids, once the node has read
+    /// back its high-water mark.
+    ids: Arc<OnceCell<Snowflake>>,
+    /// The counter up to which ids are reserved, exclusive.
+    reserved: Arc<AsyncMutex<u64>>,
 }
 
 impl Handler {
-    fn new() -> Self {
+    /// Picks the id format named by the `NODEID_FORMAT` environment variable,
+    /// defaulting to `snowflake`.
+    fn new(runtime: Runtime) -> Result<Self> {
+        let format = env::var("NODEID_FORMAT").unwrap_or_else(|_| "snowflake".to_string());
+        let format = match format.as_str() {
+            "snowflake" => Format::Snowflake,
+            "ulid" => Format::Ulid,
+            "uuidv7" => Format::UuidV7,
+            "node-counter" => Format::NodeCounter,
+            _ => return Err(format!("unknown NODEID_FORMAT {:?}", format).into()),
+        };
+        Ok(Self::with_kv(Kv::new(lin_kv(runtime)), format))
+    }
+
+    /// A handler keeping its high-water mark in `s`.
+    fn with_kv(s: Kv, format: Format) -> Self {
         Self {
-            counter: Arc::new(AtomicUsize::new(0)),
+            s,
+            format,
+            ids: Arc::new(OnceCell::new()),
+            reserved: Arc::new(AsyncMutex::new(0)),
+        }
+    }
+
+    /// Hands out `count` ids, once they are all below the high-water mark.
+    async fn generate(&self, node_id: &str, count: usize) -> Result<Vec<Id>> {
+        let ids = self.ids.get_or_try_init(|| self.load(node_id)).await?;
+        let parts: Vec<Parts> = (0..count).map(|_| ids.next_parts()).collect();
+        // Counters only grow, so the last one is the largest.
+        if let Some(last) = parts.last() {
+            let mut reserved = self.reserved.lock().await;
+            if last.counter() >= *reserved {
+                *reserved = self.reserve(node_id, last.counter()).await?;
+            }
         }
+        let ids = parts.into_iter().map(|p| self.format.format(node_id, p));
+        Ok(ids.collect())
+    }
+
+    /// A generator that skips past every id handed out before a restart. If
+    /// the mark can't be read, the next request tries again.
+    async fn load(&self, node_id: &str) -> KvResult<Snowflake> {
+        let Some(ids) = Snowflake::for_node(node_id) else {
+            return Err(KvError::Crash(format!(
+                "no node index in node id {:?}",
+                node_id
+            )));
+        };
+        let key = format!("nodeid-{}", node_id);
+        let mark = retry(|| self.s.get_or(key.clone(), 0)).await?;
+        ids.skip_past(mark);
+        Ok(ids)
+    }
+
+    /// Persists a high-water mark past `counter` and returns it. A node only
+    /// hands out ids below the mark it persisted last, so after a restart it
+    /// can skip past all of them.
+    async fn reserve(&self, node_id: &str, counter: u64) -> KvResult<u64> {
+        let key = format!("nodeid-{}", node_id);
+        retry(|| async {
+            let mark: u64 = self.s.get_or(key.clone(), 0).await?;
+            // A mark past `counter` will do, and is also how a cas we didn't
+            // hear back from shows it went through.
+            if mark > counter {
+                return Ok(mark);
+            }
+            let next = counter + RESERVE_AHEAD;
+            let reserved = self.s.cas(key.clone(), mark, next, true).await;
+            reserved.map_err(KvError::reread)?;
+            Ok(next)
+        })
+        .await
     }
 }
 
@@ -32,14 +119,27 @@ impl Node for Handler {
     async fn process(&self, runtime: Runtime, req: Message) -> Result<()> {
         let msg: Result<Request> = req.body.as_obj();
         match msg {
+            Ok(Request::Init {}) => {
+                let node_id = runtime.node_id();
+                if Snowflake::for_node(node_id).is_none() {
+                    return Err(format!("no node index in node id {:?}", node_id).into());
+                }
+            }
             Ok(Request::Echo {}) => {
                 let echo = req.body.clone().with_type("echo_ok");
                 return runtime.reply(req, echo).await;
             }
             Ok(Request::Generate {}) => {
-                let msg_id = self.counter.clone().fetch_add(1, Ordering::Relaxed);
-                let id = format!("{}-{}", runtime.node_id(), msg_id);
-                let generate = Response::GenerateOk { id };
+                let mut ids = self.generate(runtime.node_id(), 1).await?;
+                let generate = Response::GenerateOk { id: ids.remove(0) };
+                return runtime.reply(req, generate).await;
+            }
+            Ok(Request::GenerateBatch { count }) => {
+                if count > MAX_BATCH {
+                    return Err(Box::new(Error::MalformedRequest));
+                }
+                let ids = self.generate(runtime.node_id(), count).await?;
+                let generate = Response::GenerateBatchOk { ids };
                 return runtime.reply(req, generate).await;
             }
             _ => {}
@@ -48,17 +148,206 @@ impl Node for Handler {
     }
 }
 
+/// The shapes of id a node can hand out. Each one is made from the parts of
+/// a snowflake id, whose millisecond, node index and sequence number never
+/// repeat together, so ids of any format are unique across nodes and
+/// restarts. ULIDs and UUIDs fill the bits those leave over with random
+/// ones.
+#[derive(Clone, Copy)]
+enum Format {
+    /// The 64-bit integer itself.
+    Snowflake,
+    /// 48 bits of milliseconds, 16 of node index, 16 of sequence number and
+    /// 48 random, in Crockford's base32.
+    Ulid,
+    /// 48 bits of milliseconds, the version, the sequence number as
+    /// `rand_a`, then the variant and the node index followed by 52 random
+    /// bits as `rand_b`.
+    UuidV7,
+    /// The node id and a counter, like `n1-42`. The counter is the snowflake
+    /// id without its node index, see [`Parts::counter`].
+    NodeCounter,
+}
+
+impl Format {
+    fn format(&self, node_id: &str, parts: Parts) -> Id {
+        let ms = parts.ms as u128;
+        let (node, seq) = (parts.node as u128, parts.seq as u128);
+        match self {
+            Format::Snowflake => Id::Int(parts.id()),
+            Format::Ulid => {
+                let random = rand::random::<u64>() as u128 & ((1 << 48) - 1);
+                let ulid = (ms << 80) | (node << 64) | (seq << 48) | random;
+                Id::Text(crockford(ulid))
+            }
+            Format::UuidV7 => {
+                let random = rand::random::<u64>() as u128 & ((1 << 52) - 1);
+                let uuid =
+                    (ms << 80) | (0x7 << 76) | (seq << 64) | (0b10 << 62) | (node << 52) | random;
+                let hex = format!("{:032x}", uuid);
+                let groups = [
+                    &hex[..8],
+                    &hex[8..12],
+                    &hex[12..16],
+                    &hex[16..20],
+                    &hex[20..],
+                ];
+                Id::Text(groups.join("-"))
+            }
+            Format::NodeCounter => Id::Text(format!("{}-{}", node_id, parts.counter())),
+        }
+    }
+}
+
+/// Encodes 128 bits as the 26 characters of a ULID.
+fn crockford(mut n: u128) -> String {
+    const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+    let mut chars = [0u8; 26];
+    for c in chars.iter_mut().rev() {
+        *c = ALPHABET[(n & 31) as usize];
+        n >>= 5;
+    }
+    String::from_utf8_lossy(&chars).into_owned()
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Id {
+    Int(u64),
+    Text(String),
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "snake_case", tag = "type")]
 enum Request {
     Init {},
     Echo {},
     Generate {},
+    GenerateBatch { count: usize },
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "snake_case", tag = "type")]
+#[allow(clippy::enum_variant_names)]
 enum Response {
     EchoOk {},
-    GenerateOk { id: String },
+    GenerateOk { id: Id },
+    GenerateBatchOk { ids: Vec<Id> },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::future::join_all;
+    use std::collections::HashSet;
+
+    const FORMATS: [Format; 4] = [
+        Format::Snowflake,
+        Format::Ulid,
+        Format::UuidV7,
+        Format::NodeCounter,
+    ];
+
+    /// A key that sorts the ids of one node the way they were handed out.
+    fn order(id: &Id) -> String {
+        match id {
+            Id::Int(n) => format!("{:020}", n),
+            Id::Text(text) => match text.split_once('-') {
+                // A node counter, as UUIDs have more than one dash.
+                Some((_, counter)) if !counter.contains('-') => {
+                    format!("{:020}", counter.parse::<u64>().unwrap())
+                }
+                _ => text.clone(),
+            },
+        }
+    }
+
+    fn text(id: &Id) -> String {
+        match id {
+            Id::Int(n) => n.to_string(),
+            Id::Text(text) => text.clone(),
+        }
+    }
+
+    #[test]
+    fn ids_of_every_format_increase_across_restarts() {
+        for format in FORMATS {
+            let mut ids = vec![];
+            let mut last = 0;
+            for restart in 0..5 {
+                // What the node reserved before it restarted, read back.
+                let node = Snowflake::for_node("n2").unwrap();
+                node.skip_past(last + restart * RESERVE_AHEAD);
+                for _ in 0..5000 {
+                    let parts = node.next_parts();
+                    last = parts.counter();
+                    ids.push(format.format("n2", parts));
+                }
+            }
+            let order: Vec<String> = ids.iter().map(order).collect();
+            assert!(order.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+    }
+
+    #[test]
+    fn ids_of_every_format_differ_across_nodes() {
+        for format in FORMATS {
+            let mut seen = HashSet::new();
+            for n in 0..5 {
+                let node_id = format!("n{}", n);
+                let node = Snowflake::for_node(&node_id).unwrap();
+                for _ in 0..5000 {
+                    let id = format.format(&node_id, node.next_parts());
+                    assert!(seen.insert(text(&id)));
+                }
+            }
+        }
+    }
+
+    /// The counters of `count` ids `handler` hands out as node `n1`.
+    async fn counters(handler: &Handler, count: usize) -> Vec<u64> {
+        let ids = handler.generate("n1", count).await.unwrap();
+        let counter = |id: Id| text(&id)["n1-".len()..].parse().unwrap();
+        ids.into_iter().map(counter).collect()
+    }
+
+    fn mark(handler: &Handler) -> u64 {
+        block_on(handler.s.get("nodeid-n1".to_string())).unwrap()
+    }
+
+    #[test]
+    fn concurrent_requests_get_distinct_ids_below_the_mark() {
+        let handler = Handler::with_kv(Kv::memory(), Format::NodeCounter);
+        let requests = (0..40).map(|i| match i % 2 {
+            0 => counters(&handler, 1),
+            _ => counters(&handler, 100),
+        });
+        let batches = block_on(join_all(requests));
+        let all: Vec<u64> = batches.iter().flatten().copied().collect();
+        assert_eq!(all.len(), 20 + 20 * 100);
+        assert_eq!(all.iter().collect::<HashSet<_>>().len(), all.len());
+        for batch in &batches {
+            assert!(batch.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+        let mark = mark(&handler);
+        assert!(all.iter().all(|counter| *counter < mark));
+    }
+
+    #[test]
+    fn a_restarted_node_skips_past_every_id_it_handed_out() {
+        let s = Kv::memory();
+        let mut last = 0;
+        for _ in 0..3 {
+            let handler = Handler::with_kv(s.clone(), Format::NodeCounter);
+            let mark_before = block_on(s.get_or("nodeid-n1".to_string(), 0)).unwrap();
+            let requests = (0..5).map(|_| counters(&handler, 200));
+            let batches = block_on(join_all(requests));
+            let all: Vec<u64> = batches.into_iter().flatten().collect();
+            assert!(all.iter().all(|counter| *counter > last));
+            assert!(all.iter().all(|counter| *counter >= mark_before));
+            last = *all.iter().max().unwrap();
+            assert!(last < mark(&handler));
+        }
+    }
 }
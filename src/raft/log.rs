@@ -0,0 +1,176 @@
+//! The replicated log. Indexes start at 1; index 0 stands for the empty
+//! prefix before the first entry, with term 0. Once a snapshot replaces a
+//! prefix of the log, the log starts after the last entry the snapshot
+//! covers, its base, and only remembers that entry's index and term.
+
+use crate::store::Command;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    pub(crate) term: u64,
+    pub(crate) command: Command,
+}
+
+#[derive(Default)]
+pub(crate) struct Log {
+    base_index: u64,
+    base_term: u64,
+    /// The entries after the base.
+    entries: Vec<Entry>,
+}
+
+impl Log {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn base_index(&self) -> u64 {
+        self.base_index
+    }
+
+    pub(crate) fn base_term(&self) -> u64 {
+        self.base_term
+    }
+
+    pub(crate) fn last_index(&self) -> u64 {
+        self.base_index + self.entries.len() as u64
+    }
+
+    pub(crate) fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.base_term, |entry| entry.term)
+    }
+
+    /// The term of the entry at `index`, if the log reaches that far and
+    /// hasn't dropped it for a snapshot.
+    pub(crate) fn term(&self, index: u64) -> Option<u64> {
+        if index == self.base_index {
+            return Some(self.base_term);
+        }
+        self.get(index).map(|entry| entry.term)
+    }
+
+    pub(crate) fn get(&self, index: u64) -> Option<&Entry> {
+        let i = index.checked_sub(self.base_index + 1)?;
+        self.entries.get(i as usize)
+    }
+
+    /// Up to `max` entries starting at `index`, or after the base if that
+    /// comes later.
+    pub(crate) fn slice(&self, index: u64, max: usize) -> Vec<Entry> {
+        let start = (index.max(self.base_index + 1) - self.base_index - 1) as usize;
+        let start = start.min(self.entries.len());
+        let end = (start + max).min(self.entries.len());
+        self.entries[start..end].to_vec()
+    }
+
+    /// Appends `entry` and returns its index.
+    pub(crate) fn append(&mut self, entry: Entry) -> u64 {
+        self.entries.push(entry);
+        self.last_index()
+    }
+
+    /// Drops the entry at `index` and every one after it.
+    pub(crate) fn truncate(&mut self, index: u64) {
+        let keep = index.max(self.base_index + 1) - self.base_index - 1;
+        self.entries.truncate(keep as usize);
+    }
+
+    /// Makes the entry at `index`, with `term`, the base: drops it and every
+    /// one before it. A log without that entry has nothing in common with
+    /// the snapshot past it, so it drops all its entries.
+    pub(crate) fn compact(&mut self, index: u64, term: u64) {
+        match self.term(index) {
+            Some(t) if t == term => {
+                self.entries.drain(..(index - self.base_index) as usize);
+            }
+            _ => self.entries.clear(),
+        }
+        self.base_index = index;
+        self.base_term = term;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A log with entries of `terms`, at indexes 1 on.
+    fn with_terms(terms: &[u64]) -> Log {
+        let mut log = Log::new();
+        for term in terms {
+            let command = Command::Noop;
+            log.append(Entry {
+                term: *term,
+                command,
+            });
+        }
+        log
+    }
+
+    fn terms(entries: &[Entry]) -> Vec<u64> {
+        entries.iter().map(|entry| entry.term).collect()
+    }
+
+    #[test]
+    fn indexes_start_at_one() {
+        let log = with_terms(&[1, 1, 2]);
+        assert_eq!((log.last_index(), log.last_term()), (3, 2));
+        assert_eq!(log.term(0), Some(0));
+        assert_eq!(log.term(3), Some(2));
+        assert_eq!(log.term(4), None);
+        assert_eq!(terms(&log.slice(2, 10)), vec![1, 2]);
+        assert_eq!(terms(&log.slice(0, 1)), vec![1]);
+    }
+
+    #[test]
+    fn compacting_keeps_indexes_of_the_entries_after_the_base() {
+        let mut log = with_terms(&[1, 1, 2, 2, 3]);
+        log.compact(3, 2);
+        assert_eq!((log.base_index(), log.base_term()), (3, 2));
+        assert_eq!((log.last_index(), log.last_term()), (5, 3));
+        assert!(log.get(3).is_none());
+        assert_eq!(log.term(3), Some(2));
+        assert_eq!(log.term(2), None);
+        assert_eq!(log.get(4).map(|e| e.term), Some(2));
+        // Slices start after the base, however far back they are asked for.
+        assert_eq!(terms(&log.slice(1, 10)), vec![2, 3]);
+        assert_eq!(terms(&log.slice(5, 10)), vec![3]);
+        assert!(log.slice(6, 10).is_empty());
+        assert_eq!(
+            log.append(Entry {
+                term: 4,
+                command: Command::Noop
+            }),
+            6
+        );
+    }
+
+    #[test]
+    fn truncating_never_reaches_past_the_base() {
+        let mut log = with_terms(&[1, 1, 2, 2, 3]);
+        log.compact(2, 1);
+        log.truncate(4);
+        assert_eq!((log.last_index(), log.last_term()), (3, 2));
+        log.truncate(1);
+        assert_eq!((log.last_index(), log.last_term()), (2, 1));
+    }
+
+    #[test]
+    fn compacting_past_a_different_entry_drops_the_whole_log() {
+        let mut log = with_terms(&[1, 1, 2]);
+        log.compact(2, 5);
+        assert_eq!((log.base_index(), log.last_index()), (2, 2));
+        assert_eq!(log.last_term(), 5);
+
+        let mut log = with_terms(&[1, 1, 2]);
+        log.compact(7, 3);
+        assert_eq!(
+            (log.base_index(), log.last_index(), log.last_term()),
+            (7, 7, 3)
+        );
+        assert!(log.slice(1, 10).is_empty());
+    }
+}
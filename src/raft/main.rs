@@ -0,0 +1,143 @@
+use async_trait::async_trait;
+use maelstrom::protocol::Message;
+use maelstrom::{Error, Node, Result, Runtime};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_context::context::Context;
+
+mod log;
+mod replica;
+mod store;
+
+use replica::Replica;
+use store::Command;
+
+/// How long a client request may wait for its command to be applied, or for
+/// the leader it was forwarded to to answer.
+const CLIENT_TIMEOUT: Duration = Duration::from_millis(1000);
+
+pub(crate) fn main() {
+    let _ = Runtime::init(try_main());
+}
+
+async fn try_main() -> Result<()> {
+    let handler = Arc::new(Handler::new());
+    Runtime::new().with_handler(handler).run().await
+}
+
+/// A linearizable key-value store with the interface of Maelstrom's lin-kv
+/// service, replicated with Raft. Any node takes requests, forwarding them to
+/// the leader if it doesn't lead itself.
+#[derive(Clone)]
+struct Handler {
+    replica: Arc<Replica>,
+}
+
+impl Handler {
+    fn new() -> Self {
+        Self {
+            replica: Arc::new(Replica::new()),
+        }
+    }
+
+    /// Runs `command` through the log, on the leader.
+    async fn execute(
+        &self,
+        runtime: &Runtime,
+        command: Command,
+        forwarded: bool,
+    ) -> Result<Response> {
+        let reply = match self.replica.submit(runtime, command.clone()) {
+            Ok(outcome) => outcome,
+            // A leader that lost its lead forwards nothing further, so a
+            // request can't go round in circles.
+            Err(Some(leader)) if !forwarded => {
+                let (ctx, _handler) = Context::with_timeout(CLIENT_TIMEOUT);
+                let msg = runtime
+                    .call(ctx, leader, Request::Forward { command })
+                    .await?;
+                return msg.body.as_obj();
+            }
+            Err(_) => return Err(Box::new(Error::TemporarilyUnavailable)),
+        };
+        let outcome = match tokio::time::timeout(CLIENT_TIMEOUT, reply).await {
+            Ok(Ok(outcome)) => outcome,
+            // The command may still get committed later.
+            _ => return Err(Box::new(Error::Timeout)),
+        };
+        let value = outcome?;
+        Ok(match command {
+            Command::Read { .. } => Response::ReadOk { value },
+            Command::Write { .. } => Response::WriteOk {},
+            Command::Cas { .. } | Command::Noop => Response::CasOk {},
+        })
+    }
+}
+
+#[async_trait]
+impl Node for Handler {
+    async fn process(&self, runtime: Runtime, req: Message) -> Result<()> {
+        let msg: Result<Request> = req.body.as_obj();
+        let (command, forwarded) = match msg {
+            Ok(Request::Init {}) => {
+                self.replica.start(runtime);
+                return Ok(());
+            }
+            Ok(Request::Read { key }) => (Command::Read { key }, false),
+            Ok(Request::Write { key, value }) => (Command::Write { key, value }, false),
+            Ok(Request::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists: create,
+            }) => (
+                Command::Cas {
+                    key,
+                    from,
+                    to,
+                    create,
+                },
+                false,
+            ),
+            Ok(Request::Forward { command }) => (command, true),
+            Err(_) => return self.replica.process(runtime, req).await,
+        };
+        let response = self.execute(&runtime, command, forwarded).await?;
+        runtime.reply(req, response).await
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum Request {
+    Init {},
+    Read {
+        key: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+    /// A client request sent on to the leader.
+    Forward {
+        command: Command,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+#[allow(clippy::enum_variant_names)]
+enum Response {
+    ReadOk { value: Option<Value> },
+    WriteOk {},
+    CasOk {},
+}
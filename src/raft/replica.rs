@@ -0,0 +1,888 @@
+//! The Raft core: leader election, log replication and commitment.
+//!
+//! Every node starts as a follower. One that hears nothing from a leader for
+//! a randomized election timeout becomes a candidate for the next term and
+//! asks the others for their votes; the one that gets a majority leads that
+//! term. The leader appends the commands it is sent to its log and sends the
+//! entries to the followers, which keep their logs identical to its own. An
+//! entry is committed once a majority stores it, and every node applies the
+//! committed entries to its [`Store`] in order.
+//!
+//! So that the log doesn't grow forever, every node replaces the entries it
+//! applied with a snapshot of its store from time to time. A follower so far
+//! behind that the leader no longer has the entries it is missing gets the
+//! leader's snapshot instead.
+
+use crate::log::{Entry, Log};
+use crate::store::{Command, Outcome, Store};
+use maelstrom::protocol::Message;
+use maelstrom::{done, Error, Result, Runtime};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+use tokio_context::context::Context;
+
+/// How often a node checks whether a timeout expired.
+const TICK: Duration = Duration::from_millis(10);
+
+/// How long a follower waits to hear from a leader before running for
+/// election, at least; each wait adds up to as much again at random, so
+/// nodes rarely run at the same time.
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// How often a leader sends followers entries, or nothing, to hold off their
+/// elections.
+const HEARTBEAT: Duration = Duration::from_millis(50);
+
+/// How long to wait for another node to answer a vote or append.
+const RPC_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// The most entries sent in one append.
+const MAX_ENTRIES: usize = 64;
+
+/// How many applied entries a node keeps in its log before it replaces them
+/// with a snapshot.
+const SNAPSHOT_EVERY: u64 = 256;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+pub(crate) struct Replica {
+    inner: Mutex<State>,
+}
+
+struct State {
+    role: Role,
+    term: u64,
+    voted_for: Option<String>,
+    /// The leader of the current term, once known.
+    leader: Option<String>,
+    log: Log,
+    commit: u64,
+    applied: u64,
+    store: Store,
+    /// The store as of the log's base, which followers the log no longer
+    /// reaches back far enough for get instead of entries.
+    snapshot: Store,
+    /// When a follower or candidate runs for election, or a leader sends its
+    /// next heartbeat.
+    deadline: Instant,
+    votes: HashSet<String>,
+    /// For each follower, the index of the next entry to send it and the
+    /// highest index known to match the leader's log.
+    next: HashMap<String, u64>,
+    matched: HashMap<String, u64>,
+    /// Followers with an append on its way, which get no other until it is
+    /// answered.
+    inflight: HashSet<String>,
+    /// Clients waiting for the entry at an index, with the term it was
+    /// appended in: if a different entry gets committed there, theirs never
+    /// will be.
+    waiters: HashMap<u64, (u64, oneshot::Sender<Outcome>)>,
+}
+
+impl Replica {
+    pub(crate) fn new() -> Self {
+        Self {
+            inner: Mutex::new(State::new()),
+        }
+    }
+
+    /// Starts the timers driving elections and heartbeats.
+    pub(crate) fn start(self: &Arc<Self>, runtime: Runtime) {
+        let replica = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(TICK).await;
+                replica.tick(&runtime);
+            }
+        });
+    }
+
+    /// Appends `command` to the log if this node leads, and returns a channel
+    /// that gets its outcome once it is applied. Otherwise returns the leader
+    /// to try instead, if this node knows it.
+    pub(crate) fn submit(
+        self: &Arc<Self>,
+        runtime: &Runtime,
+        command: Command,
+    ) -> StdResult<oneshot::Receiver<Outcome>, Option<String>> {
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut state = self.inner.lock().unwrap();
+            if state.role != Role::Leader {
+                return Err(state.leader.clone());
+            }
+            let term = state.term;
+            let index = state.log.append(Entry { term, command });
+            state.waiters.insert(index, (term, tx));
+            state.advance_commit(runtime);
+        }
+        self.replicate(runtime);
+        Ok(rx)
+    }
+
+    fn tick(self: &Arc<Self>, runtime: &Runtime) {
+        let mut state = self.inner.lock().unwrap();
+        if Instant::now() < state.deadline {
+            return;
+        }
+        match state.role {
+            Role::Leader => {
+                state.deadline = Instant::now() + HEARTBEAT;
+                drop(state);
+                self.replicate(runtime);
+            }
+            Role::Follower | Role::Candidate => {
+                let Some(vote) = state.campaign(runtime) else {
+                    drop(state);
+                    self.replicate(runtime);
+                    return;
+                };
+                let term = state.term;
+                drop(state);
+                for n in runtime.neighbours() {
+                    let (replica, r0, n) = (self.clone(), runtime.clone(), n.clone());
+                    let vote = vote.clone();
+                    tokio::spawn(async move {
+                        let (ctx, _handler) = Context::with_timeout(RPC_TIMEOUT);
+                        let reply = r0.call(ctx, n.clone(), vote).await;
+                        let reply = reply.and_then(|msg| msg.body.as_obj::<Response>());
+                        replica.on_vote(&r0, term, n, reply.ok());
+                    });
+                }
+            }
+        }
+    }
+
+    fn on_vote(
+        self: &Arc<Self>,
+        runtime: &Runtime,
+        term: u64,
+        from: String,
+        reply: Option<Response>,
+    ) {
+        let won = self
+            .inner
+            .lock()
+            .unwrap()
+            .on_vote(runtime, term, &from, reply);
+        if won {
+            self.replicate(runtime);
+        }
+    }
+
+    /// Sends every follower without an append on its way the entries it is
+    /// missing, or an empty append as a heartbeat. One missing entries the
+    /// log has dropped gets the snapshot instead.
+    fn replicate(self: &Arc<Self>, runtime: &Runtime) {
+        let appends = self.inner.lock().unwrap().appends(runtime);
+        for (n, term, sent_up_to, append) in appends {
+            let (replica, r0) = (self.clone(), runtime.clone());
+            tokio::spawn(async move {
+                let (ctx, _handler) = Context::with_timeout(RPC_TIMEOUT);
+                let reply = r0.call(ctx, n.clone(), append).await;
+                let reply = reply.and_then(|msg| msg.body.as_obj::<Response>());
+                replica.on_append(&r0, n, term, sent_up_to, reply.ok());
+            });
+        }
+    }
+
+    fn on_append(
+        self: &Arc<Self>,
+        runtime: &Runtime,
+        from: String,
+        term: u64,
+        sent_up_to: u64,
+        reply: Option<Response>,
+    ) {
+        let behind = self
+            .inner
+            .lock()
+            .unwrap()
+            .on_append(runtime, &from, term, sent_up_to, reply);
+        if behind {
+            self.replicate(runtime);
+        }
+    }
+
+    /// Handles the messages nodes exchange among themselves.
+    pub(crate) async fn process(&self, runtime: Runtime, req: Message) -> Result<()> {
+        let msg: Result<Request> = req.body.as_obj();
+        let Ok(msg) = msg else {
+            return done(runtime, req);
+        };
+        let response = self.inner.lock().unwrap().handle(msg);
+        runtime.reply(req, response).await
+    }
+}
+
+impl State {
+    fn new() -> Self {
+        Self {
+            role: Role::Follower,
+            term: 0,
+            voted_for: None,
+            leader: None,
+            log: Log::new(),
+            commit: 0,
+            applied: 0,
+            store: Store::default(),
+            snapshot: Store::default(),
+            deadline: election_deadline(),
+            votes: HashSet::new(),
+            next: HashMap::new(),
+            matched: HashMap::new(),
+            inflight: HashSet::new(),
+            waiters: HashMap::new(),
+        }
+    }
+
+    /// The appends a leader sends every follower without one on its way:
+    /// the entries it is missing, or none as a heartbeat, or the snapshot if
+    /// the log has dropped them. Each comes with the follower, the term and
+    /// the last index it covers.
+    fn appends(&mut self, runtime: &Runtime) -> Vec<(String, u64, u64, Request)> {
+        let mut appends = vec![];
+        if self.role != Role::Leader {
+            return appends;
+        }
+        for n in runtime.neighbours() {
+            if !self.inflight.insert(n.clone()) {
+                continue;
+            }
+            let next = self.next[n];
+            if next <= self.log.base_index() {
+                let install = Request::InstallSnapshot {
+                    term: self.term,
+                    leader: runtime.node_id().to_string(),
+                    last_index: self.log.base_index(),
+                    last_term: self.log.base_term(),
+                    store: self.snapshot.clone(),
+                };
+                appends.push((n.clone(), self.term, self.log.base_index(), install));
+                continue;
+            }
+            let prev_index = next - 1;
+            let entries = self.log.slice(next, MAX_ENTRIES);
+            let sent_up_to = prev_index + entries.len() as u64;
+            let append = Request::AppendEntries {
+                term: self.term,
+                leader: runtime.node_id().to_string(),
+                prev_index,
+                prev_term: self.log.term(prev_index).unwrap_or(0),
+                entries,
+                commit: self.commit,
+            };
+            appends.push((n.clone(), self.term, sent_up_to, append));
+        }
+        appends
+    }
+
+    /// Records how an append to `from` in `term`, covering the log up to
+    /// `sent_up_to`, went. Returns whether the follower is still behind.
+    fn on_append(
+        &mut self,
+        runtime: &Runtime,
+        from: &str,
+        term: u64,
+        sent_up_to: u64,
+        reply: Option<Response>,
+    ) -> bool {
+        self.inflight.remove(from);
+        let (t, success, last_index) = match reply {
+            Some(Response::AppendEntriesOk {
+                term,
+                success,
+                last_index,
+            }) => (term, success, last_index),
+            Some(Response::InstallSnapshotOk { term }) => (term, true, sent_up_to),
+            _ => return false,
+        };
+        if t > self.term {
+            self.step_down(t);
+            return false;
+        }
+        if self.role != Role::Leader || self.term != term {
+            return false;
+        }
+        if success {
+            let matched = self.matched[from].max(sent_up_to);
+            self.matched.insert(from.to_string(), matched);
+            self.next.insert(from.to_string(), matched + 1);
+            self.advance_commit(runtime);
+        } else {
+            // Back up to what the follower has at most, and retry from there.
+            let next = (self.next[from] - 1).min(last_index + 1).max(1);
+            self.next.insert(from.to_string(), next);
+        }
+        self.next[from] <= self.log.last_index()
+    }
+
+    /// Runs for election in the next term, voting for itself. Returns the
+    /// request for the other nodes' votes, or `None` if its own vote is
+    /// enough and it leads already.
+    fn campaign(&mut self, runtime: &Runtime) -> Option<Request> {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.voted_for = Some(runtime.node_id().to_string());
+        self.votes = HashSet::from([runtime.node_id().to_string()]);
+        self.leader = None;
+        self.deadline = election_deadline();
+        if self.won(runtime) {
+            self.lead(runtime);
+            return None;
+        }
+        Some(Request::RequestVote {
+            term: self.term,
+            candidate: runtime.node_id().to_string(),
+            last_log_index: self.log.last_index(),
+            last_log_term: self.log.last_term(),
+        })
+    }
+
+    /// Counts the answer `from` gave to the vote requested in `term`.
+    /// Returns whether this node just won the election.
+    fn on_vote(
+        &mut self,
+        runtime: &Runtime,
+        term: u64,
+        from: &str,
+        reply: Option<Response>,
+    ) -> bool {
+        let Some(Response::RequestVoteOk { term: t, granted }) = reply else {
+            return false;
+        };
+        if t > self.term {
+            self.step_down(t);
+            return false;
+        }
+        if self.role != Role::Candidate || self.term != term || !granted {
+            return false;
+        }
+        self.votes.insert(from.to_string());
+        if !self.won(runtime) {
+            return false;
+        }
+        self.lead(runtime);
+        true
+    }
+
+    /// Answers another node's request.
+    fn handle(&mut self, req: Request) -> Response {
+        match req {
+            Request::RequestVote {
+                term,
+                candidate,
+                last_log_index,
+                last_log_term,
+            } => {
+                if term > self.term {
+                    self.step_down(term);
+                }
+                let ours = (self.log.last_term(), self.log.last_index());
+                let free = match &self.voted_for {
+                    Some(voted_for) => *voted_for == candidate,
+                    None => true,
+                };
+                let granted = term == self.term && free && (last_log_term, last_log_index) >= ours;
+                if granted {
+                    self.voted_for = Some(candidate);
+                    self.deadline = election_deadline();
+                }
+                Response::RequestVoteOk {
+                    term: self.term,
+                    granted,
+                }
+            }
+            Request::AppendEntries {
+                term,
+                leader,
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+            } => {
+                let success = term >= self.term && {
+                    if term > self.term || self.role != Role::Follower {
+                        self.step_down(term);
+                    }
+                    self.leader = Some(leader);
+                    self.deadline = election_deadline();
+                    self.append(prev_index, prev_term, entries, commit)
+                };
+                Response::AppendEntriesOk {
+                    term: self.term,
+                    success,
+                    last_index: self.log.last_index(),
+                }
+            }
+            Request::InstallSnapshot {
+                term,
+                leader,
+                last_index,
+                last_term,
+                store,
+            } => {
+                if term >= self.term {
+                    if term > self.term || self.role != Role::Follower {
+                        self.step_down(term);
+                    }
+                    self.leader = Some(leader);
+                    self.deadline = election_deadline();
+                    self.install(last_index, last_term, store);
+                }
+                Response::InstallSnapshotOk { term: self.term }
+            }
+        }
+    }
+
+    fn won(&self, runtime: &Runtime) -> bool {
+        self.votes.len() * 2 > runtime.nodes().len()
+    }
+
+    fn lead(&mut self, runtime: &Runtime) {
+        self.role = Role::Leader;
+        self.leader = Some(runtime.node_id().to_string());
+        let next = self.log.last_index() + 1;
+        self.next = runtime.neighbours().map(|n| (n.clone(), next)).collect();
+        self.matched = runtime.neighbours().map(|n| (n.clone(), 0)).collect();
+        self.inflight.clear();
+        let term = self.term;
+        self.log.append(Entry {
+            term,
+            command: Command::Noop,
+        });
+        self.deadline = Instant::now() + HEARTBEAT;
+        self.advance_commit(runtime);
+    }
+
+    /// Follows whoever leads `term`, which is at least the current one.
+    fn step_down(&mut self, term: u64) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.leader = None;
+        }
+        self.role = Role::Follower;
+        self.deadline = election_deadline();
+    }
+
+    /// Makes the log agree with the leader's: `entries` follow the entry at
+    /// `prev_index`, which has to be there with `prev_term`. Returns whether
+    /// it was.
+    fn append(
+        &mut self,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+    ) -> bool {
+        let last = prev_index + entries.len() as u64;
+        // Entries up to the base are committed, so they match the leader's.
+        let skip = self.log.base_index().saturating_sub(prev_index);
+        let (prev_index, prev_term) = match skip {
+            0 => (prev_index, prev_term),
+            _ => (self.log.base_index(), self.log.base_term()),
+        };
+        if self.log.term(prev_index) != Some(prev_term) {
+            return false;
+        }
+        let entries = entries.into_iter().skip(skip as usize);
+        for (index, entry) in (prev_index + 1..).zip(entries) {
+            match self.log.term(index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => self.log.truncate(index),
+                None => {}
+            }
+            self.log.append(entry);
+        }
+        let commit = commit.min(last);
+        if commit > self.commit {
+            self.commit = commit;
+            self.apply();
+        }
+        true
+    }
+
+    /// Commits everything up to the highest entry of the current term a
+    /// majority stores. Entries of earlier terms get committed along with it.
+    fn advance_commit(&mut self, runtime: &Runtime) {
+        if self.role != Role::Leader {
+            return;
+        }
+        let majority = runtime.nodes().len() / 2 + 1;
+        for index in (self.commit + 1..=self.log.last_index()).rev() {
+            if self.log.term(index) != Some(self.term) {
+                break;
+            }
+            let stored = 1 + self.matched.values().filter(|m| **m >= index).count();
+            if stored >= majority {
+                self.commit = index;
+                break;
+            }
+        }
+        self.apply();
+    }
+
+    fn apply(&mut self) {
+        while self.applied < self.commit {
+            let Some(entry) = self.log.get(self.applied + 1) else {
+                break;
+            };
+            self.applied += 1;
+            let (term, outcome) = (entry.term, self.store.apply(&entry.command));
+            if let Some((waiting, tx)) = self.waiters.remove(&self.applied) {
+                let outcome = match waiting == term {
+                    true => outcome,
+                    false => Err(Error::TemporarilyUnavailable),
+                };
+                let _ = tx.send(outcome);
+            }
+        }
+        if self.applied - self.log.base_index() >= SNAPSHOT_EVERY {
+            let term = self.log.term(self.applied).unwrap_or(0);
+            self.log.compact(self.applied, term);
+            self.snapshot = self.store.clone();
+        }
+    }
+
+    /// Takes on the leader's snapshot, which covers the log up to
+    /// `last_index`, unless it has applied that much already.
+    fn install(&mut self, last_index: u64, last_term: u64, store: Store) {
+        if last_index <= self.applied {
+            return;
+        }
+        self.log.compact(last_index, last_term);
+        self.store = store.clone();
+        self.snapshot = store;
+        self.commit = last_index;
+        self.applied = last_index;
+        // Whether these clients' entries made it into the snapshot is
+        // unknown, so they time out.
+        self.waiters.retain(|index, _| *index > last_index);
+    }
+}
+
+fn election_deadline() -> Instant {
+    let jitter = rand::thread_rng().gen_range(Duration::ZERO..ELECTION_TIMEOUT);
+    Instant::now() + ELECTION_TIMEOUT + jitter
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+#[allow(clippy::enum_variant_names)]
+enum Request {
+    RequestVote {
+        term: u64,
+        candidate: String,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    AppendEntries {
+        term: u64,
+        leader: String,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+    },
+    /// The whole snapshot in one message, rather than in chunks.
+    InstallSnapshot {
+        term: u64,
+        leader: String,
+        last_index: u64,
+        last_term: u64,
+        store: Store,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+#[allow(clippy::enum_variant_names)]
+enum Response {
+    RequestVoteOk {
+        term: u64,
+        granted: bool,
+    },
+    AppendEntriesOk {
+        term: u64,
+        success: bool,
+        last_index: u64,
+    },
+    InstallSnapshotOk {
+        term: u64,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gossip_glomers::testing;
+    use serde_json::json;
+
+    struct Node {
+        runtime: Runtime,
+        state: State,
+    }
+
+    /// `n` nodes, all of them followers in term 0.
+    fn followers(n: usize) -> Vec<Node> {
+        let node = |runtime| {
+            let state = State::new();
+            Node { runtime, state }
+        };
+        testing::cluster(n).into_iter().map(node).collect()
+    }
+
+    /// `n` nodes, the first of which leads term 1.
+    fn cluster(n: usize) -> Vec<Node> {
+        let mut nodes = followers(n);
+        let leader = &mut nodes[0];
+        leader.state.term = 1;
+        leader.state.lead(&leader.runtime);
+        nodes
+    }
+
+    /// Has `nodes[candidate]` run for election, asking every other node for
+    /// its vote unless it is in `cut`. Returns whether it won.
+    fn campaign(nodes: &mut [Node], candidate: usize, cut: &[&str]) -> bool {
+        let runtime = nodes[candidate].runtime.clone();
+        let Some(vote) = nodes[candidate].state.campaign(&runtime) else {
+            return true;
+        };
+        let term = nodes[candidate].state.term;
+        let mut won = false;
+        for i in 0..nodes.len() {
+            let to = nodes[i].runtime.node_id().to_string();
+            if i == candidate || cut.contains(&to.as_str()) {
+                continue;
+            }
+            let reply = nodes[i].state.handle(vote.clone());
+            won |= nodes[candidate]
+                .state
+                .on_vote(&runtime, term, &to, Some(reply));
+        }
+        won
+    }
+
+    fn write(leader: &mut Node, i: u64) {
+        let command = Command::Write {
+            key: json!(i % 10),
+            value: json!(i),
+        };
+        let term = leader.state.term;
+        leader.state.log.append(Entry { term, command });
+    }
+
+    /// Sends every follower the appends of `nodes[leader]`, unless it is in
+    /// `cut`, in which case the append times out.
+    fn replicate(nodes: &mut [Node], leader: usize, cut: &[&str]) {
+        let runtime = nodes[leader].runtime.clone();
+        for (to, term, sent_up_to, req) in nodes[leader].state.appends(&runtime) {
+            let follower = nodes.iter_mut().find(|f| f.runtime.node_id() == to);
+            let reply = match cut.contains(&to.as_str()) {
+                true => None,
+                false => Some(follower.unwrap().state.handle(req)),
+            };
+            nodes[leader]
+                .state
+                .on_append(&runtime, &to, term, sent_up_to, reply);
+        }
+    }
+
+    #[test]
+    fn a_partitioned_follower_catches_up_from_the_snapshot() {
+        let mut nodes = cluster(3);
+        for i in 0..3 * SNAPSHOT_EVERY {
+            write(&mut nodes[0], i);
+            replicate(&mut nodes, 0, &["n2"]);
+        }
+        for _ in 0..10 {
+            replicate(&mut nodes, 0, &["n2"]);
+        }
+        let leader = &nodes[0].state;
+        assert_eq!(leader.commit, leader.log.last_index());
+        assert!(leader.log.base_index() >= 2 * SNAPSHOT_EVERY);
+        assert_eq!(nodes[2].state.log.last_index(), 0);
+
+        for _ in 0..20 {
+            replicate(&mut nodes, 0, &[]);
+        }
+        let (leader, follower) = (&nodes[0].state, &nodes[2].state);
+        // It got the snapshot, not the entries before the leader's base.
+        assert!(follower.log.base_index() >= 2 * SNAPSHOT_EVERY);
+        assert!(follower.log.get(1).is_none());
+        assert_eq!(follower.log.last_index(), leader.log.last_index());
+        assert_eq!(follower.applied, leader.applied);
+        assert_eq!(follower.store, leader.store);
+    }
+
+    #[test]
+    fn a_lagging_follower_catches_up_from_the_log() {
+        let mut nodes = cluster(3);
+        for i in 0..SNAPSHOT_EVERY / 2 {
+            write(&mut nodes[0], i);
+            replicate(&mut nodes, 0, &["n2"]);
+        }
+        for _ in 0..10 {
+            replicate(&mut nodes, 0, &[]);
+        }
+        let (leader, follower) = (&nodes[0].state, &nodes[2].state);
+        assert_eq!(follower.log.base_index(), 0);
+        assert_eq!(follower.log.last_index(), leader.log.last_index());
+        assert_eq!(follower.store, leader.store);
+    }
+
+    #[test]
+    fn nodes_vote_for_one_candidate_per_term() {
+        let mut nodes = followers(3);
+        assert!(campaign(&mut nodes, 1, &["n2"]));
+        assert!(nodes[1].state.role == Role::Leader);
+        assert_eq!(nodes[0].state.voted_for.as_deref(), Some("n1"));
+
+        // n2 runs in the same term, and n0 already voted in it.
+        let runtime = nodes[2].runtime.clone();
+        let vote = nodes[2].state.campaign(&runtime).unwrap();
+        let reply = nodes[0].state.handle(vote.clone());
+        assert!(matches!(
+            reply,
+            Response::RequestVoteOk { granted: false, .. }
+        ));
+        // Asking again is fine as long as it is the same candidate.
+        let vote = Request::RequestVote {
+            term: 1,
+            candidate: "n1".to_string(),
+            last_log_index: 0,
+            last_log_term: 0,
+        };
+        let reply = nodes[0].state.handle(vote);
+        assert!(matches!(
+            reply,
+            Response::RequestVoteOk { granted: true, .. }
+        ));
+    }
+
+    #[test]
+    fn candidates_need_a_log_at_least_as_up_to_date() {
+        let vote = |last_log_term, last_log_index| Request::RequestVote {
+            term: 5,
+            candidate: "n1".to_string(),
+            last_log_index,
+            last_log_term,
+        };
+        let granted = |term, index| {
+            let mut voter = State::new();
+            for term in [1, 2, 2] {
+                let command = Command::Noop;
+                voter.log.append(Entry { term, command });
+            }
+            let reply = voter.handle(vote(term, index));
+            // Refused or not, the voter moves on to the candidate's term.
+            assert_eq!(voter.term, 5);
+            matches!(reply, Response::RequestVoteOk { granted: true, .. })
+        };
+        assert!(granted(2, 3));
+        assert!(granted(2, 4));
+        assert!(granted(3, 1));
+        assert!(!granted(2, 2));
+        assert!(!granted(1, 9));
+    }
+
+    #[test]
+    fn a_higher_term_makes_leaders_and_candidates_step_down() {
+        let mut nodes = cluster(3);
+        let leader = &mut nodes[0];
+        let reply = Response::AppendEntriesOk {
+            term: 4,
+            success: false,
+            last_index: 0,
+        };
+        let runtime = leader.runtime.clone();
+        leader.state.appends(&runtime);
+        leader.state.on_append(&runtime, "n1", 1, 1, Some(reply));
+        assert!(leader.state.role == Role::Follower);
+        assert_eq!(leader.state.term, 4);
+
+        let candidate = &mut nodes[1];
+        let runtime = candidate.runtime.clone();
+        candidate.state.campaign(&runtime).unwrap();
+        let reply = Response::RequestVoteOk {
+            term: 7,
+            granted: false,
+        };
+        assert!(!candidate.state.on_vote(&runtime, 1, "n2", Some(reply)));
+        assert!(candidate.state.role == Role::Follower);
+        assert_eq!(candidate.state.term, 7);
+    }
+
+    #[test]
+    fn only_entries_of_the_current_term_are_counted_toward_commit() {
+        let mut nodes = followers(3);
+        let leader = &mut nodes[0];
+        for _ in 0..2 {
+            let command = Command::Noop;
+            leader.state.log.append(Entry { term: 1, command });
+        }
+        leader.state.term = 2;
+        let runtime = leader.runtime.clone();
+        leader.state.lead(&runtime);
+        assert_eq!(leader.state.log.last_index(), 3);
+
+        // A majority stores the entries of term 1, but not the leader's own.
+        leader.state.appends(&runtime);
+        let stored = |last_index| {
+            Some(Response::AppendEntriesOk {
+                term: 2,
+                success: true,
+                last_index,
+            })
+        };
+        leader.state.on_append(&runtime, "n1", 2, 2, stored(2));
+        assert_eq!(leader.state.commit, 0);
+
+        leader.state.appends(&runtime);
+        leader.state.on_append(&runtime, "n1", 2, 3, stored(3));
+        assert_eq!(leader.state.commit, 3);
+    }
+
+    #[test]
+    fn a_leader_cut_off_from_the_majority_commits_nothing() {
+        let mut nodes = cluster(3);
+        replicate(&mut nodes, 0, &[]);
+        assert_eq!(nodes[0].state.commit, 1);
+
+        write(&mut nodes[0], 1);
+        replicate(&mut nodes, 0, &["n1", "n2"]);
+        assert_eq!(nodes[0].state.commit, 1);
+
+        // The other two elect a leader of their own, which commits.
+        assert!(campaign(&mut nodes, 1, &["n0"]));
+        assert!(nodes[0].state.role == Role::Leader);
+        write(&mut nodes[1], 2);
+        replicate(&mut nodes, 1, &["n0"]);
+        replicate(&mut nodes, 1, &["n0"]);
+        assert_eq!(nodes[1].state.commit, 3);
+
+        // Once the partition heals, the old leader follows the new one, and
+        // its entry that never got committed gives way.
+        replicate(&mut nodes, 1, &[]);
+        replicate(&mut nodes, 1, &[]);
+        let (old, new) = (&nodes[0].state, &nodes[1].state);
+        assert!(old.role == Role::Follower);
+        assert_eq!(old.term, 2);
+        assert_eq!(old.log.term(2), Some(2));
+        assert_eq!(old.log.last_index(), new.log.last_index());
+        assert_eq!(old.commit, 3);
+        assert_eq!(old.store, new.store);
+    }
+}
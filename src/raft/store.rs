@@ -0,0 +1,71 @@
+//! The state machine the log drives: a map from keys to values, with the
+//! operations of Maelstrom's lin-kv service.
+
+use maelstrom::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::result::Result as StdResult;
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Command {
+    Read {
+        key: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        create: bool,
+    },
+    /// What a new leader appends first, as it can only commit entries of
+    /// earlier terms along with one of its own.
+    Noop,
+}
+
+/// The result of applying a command: the value read, if it was a read.
+pub(crate) type Outcome = StdResult<Option<Value>, Error>;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Store {
+    /// Values by the JSON text of their key, since keys can be any JSON.
+    data: HashMap<String, Value>,
+}
+
+impl Store {
+    pub(crate) fn apply(&mut self, command: &Command) -> Outcome {
+        match command {
+            Command::Read { key } => match self.data.get(&key.to_string()) {
+                Some(value) => Ok(Some(value.clone())),
+                None => Err(Error::KeyDoesNotExist),
+            },
+            Command::Write { key, value } => {
+                self.data.insert(key.to_string(), value.clone());
+                Ok(None)
+            }
+            Command::Cas {
+                key,
+                from,
+                to,
+                create,
+            } => match self.data.get_mut(&key.to_string()) {
+                Some(value) if value == from => {
+                    *value = to.clone();
+                    Ok(None)
+                }
+                Some(_) => Err(Error::PreconditionFailed),
+                None if *create => {
+                    self.data.insert(key.to_string(), to.clone());
+                    Ok(None)
+                }
+                None => Err(Error::KeyDoesNotExist),
+            },
+            Command::Noop => Ok(None),
+        }
+    }
+}
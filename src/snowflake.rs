@@ -0,0 +1,222 @@
+//! Snowflake ids: 64-bit integers unique across nodes and restarts, laid
+//! out from the most significant bit as
+//!
+//! - 1 unused bit, so ids stay positive as signed integers;
+//! - 41 bits of milliseconds since [`EPOCH_MS`], enough for 69 years;
+//! - 10 bits of node index;
+//! - 12 bits of sequence number within the millisecond.
+//!
+//! A node that runs out of sequence numbers within a millisecond moves on to
+//! the next one instead of waiting for it, and a node whose clock goes
+//! backwards keeps counting from the last millisecond it used. Either way its
+//! ids run ahead of its clock for a while, but never repeat. A restarted node
+//! only remembers where it was if told with [`Snowflake::skip_past`]; until
+//! then it relies on its clock having moved past its last ids.
+
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 2020-01-01T00:00:00Z, where the timestamps of ids start.
+pub const EPOCH_MS: u64 = 1_577_836_800_000;
+
+const NODE_BITS: u32 = 10;
+const SEQ_BITS: u32 = 12;
+pub const MAX_NODE: u64 = (1 << NODE_BITS) - 1;
+const MAX_SEQ: u64 = (1 << SEQ_BITS) - 1;
+
+pub struct Snowflake {
+    node: u64,
+    /// The millisecond and sequence number of the last id.
+    last: Mutex<(u64, u64)>,
+}
+
+/// The parts an id is made of.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Parts {
+    /// Milliseconds since the Unix epoch.
+    pub ms: u64,
+    pub node: u64,
+    pub seq: u64,
+}
+
+impl Snowflake {
+    /// A generator for the node with index `node`, which has to be at most
+    /// [`MAX_NODE`].
+    pub fn new(node: u64) -> Option<Self> {
+        if node > MAX_NODE {
+            return None;
+        }
+        Some(Self {
+            node,
+            last: Mutex::new((0, 0)),
+        })
+    }
+
+    /// A generator for a Maelstrom node, taking its index from its id: `n3`
+    /// is node 3.
+    pub fn for_node(node_id: &str) -> Option<Self> {
+        let node = node_id.strip_prefix('n')?.parse().ok()?;
+        Self::new(node)
+    }
+
+    /// Makes every id from now on come after the one with `counter`, which
+    /// may have been handed out before a restart.
+    pub fn skip_past(&self, counter: u64) {
+        let mut last = self.last.lock().unwrap();
+        let after = (counter >> SEQ_BITS, counter & MAX_SEQ);
+        *last = (*last).max(after);
+    }
+
+    /// The parts of the next id, each larger than the one before.
+    pub fn next_parts(&self) -> Parts {
+        self.next_parts_at(wall_clock())
+    }
+
+    /// The parts of the next id when the wall clock reads `now`, in
+    /// milliseconds since the Unix epoch.
+    fn next_parts_at(&self, now: u64) -> Parts {
+        let now = now.saturating_sub(EPOCH_MS);
+        let mut last = self.last.lock().unwrap();
+        let (ms, seq) = *last;
+        *last = match (now > ms, seq < MAX_SEQ) {
+            (true, _) => (now, 0),
+            (false, true) => (ms, seq + 1),
+            (false, false) => (ms + 1, 0),
+        };
+        Parts {
+            ms: last.0 + EPOCH_MS,
+            node: self.node,
+            seq: last.1,
+        }
+    }
+}
+
+impl Parts {
+    pub fn id(&self) -> u64 {
+        let ms = self.ms - EPOCH_MS;
+        (ms << (NODE_BITS + SEQ_BITS)) | (self.node << SEQ_BITS) | self.seq
+    }
+
+    /// The id without its node index: a number that grows with every id a
+    /// node hands out.
+    pub fn counter(&self) -> u64 {
+        ((self.ms - EPOCH_MS) << SEQ_BITS) | self.seq
+    }
+}
+
+fn wall_clock() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use std::collections::HashSet;
+
+    const START_MS: u64 = EPOCH_MS + 1_000_000;
+
+    /// Hands out `count` ids from `ids` while the clock wanders: mostly
+    /// standing still or moving ahead, sometimes going back by up to a
+    /// second.
+    fn wander(ids: &Snowflake, clock: &mut u64, count: usize) -> Vec<Parts> {
+        let mut rng = rand::thread_rng();
+        (0..count)
+            .map(|_| {
+                *clock = match rng.gen_range(0..10) {
+                    0 => clock.saturating_sub(rng.gen_range(1..1000)),
+                    1..=4 => *clock + rng.gen_range(1..5),
+                    _ => *clock,
+                };
+                ids.next_parts_at(*clock)
+            })
+            .collect()
+    }
+
+    fn assert_increasing(parts: &[Parts]) {
+        for pair in parts.windows(2) {
+            assert!(pair[0].id() < pair[1].id(), "{:?}", pair);
+            assert!(pair[0].counter() < pair[1].counter(), "{:?}", pair);
+        }
+    }
+
+    #[test]
+    fn ids_increase_while_the_clock_wanders() {
+        let ids = Snowflake::new(3).unwrap();
+        let mut clock = START_MS;
+        let parts = wander(&ids, &mut clock, 100_000);
+        assert_increasing(&parts);
+        assert!(parts.iter().all(|p| p.node == 3 && p.seq <= MAX_SEQ));
+    }
+
+    #[test]
+    fn ids_increase_past_a_full_millisecond() {
+        let ids = Snowflake::new(0).unwrap();
+        let parts: Vec<Parts> = (0..3 * (MAX_SEQ + 1))
+            .map(|_| ids.next_parts_at(START_MS))
+            .collect();
+        assert_increasing(&parts);
+        assert_eq!(parts.last().unwrap().ms, START_MS + 2);
+    }
+
+    #[test]
+    fn ids_increase_past_skipped_counters() {
+        let mut rng = rand::thread_rng();
+        let ids = Snowflake::new(7).unwrap();
+        let mut clock = START_MS;
+        let mut parts = vec![];
+        for _ in 0..1000 {
+            parts.extend(wander(&ids, &mut clock, 100));
+            // Behind, at or ahead of the last id, as a mark read back after
+            // a restart may be.
+            let last = parts.last().unwrap().counter();
+            let skip = match rng.gen_range(0..3) {
+                0 => last.saturating_sub(rng.gen_range(0..1 << 20)),
+                1 => last,
+                _ => last + rng.gen_range(0..1 << 20),
+            };
+            ids.skip_past(skip);
+            let next = ids.next_parts_at(clock);
+            assert!(next.counter() > skip);
+            parts.push(next);
+        }
+        assert_increasing(&parts);
+    }
+
+    #[test]
+    fn ids_increase_across_a_restart_with_the_clock_behind() {
+        let before = Snowflake::new(1).unwrap();
+        let mut clock = START_MS;
+        let mut parts = wander(&before, &mut clock, 10_000);
+
+        let after = Snowflake::new(1).unwrap();
+        after.skip_past(parts.last().unwrap().counter());
+        let mut clock = START_MS - 5_000;
+        parts.extend(wander(&after, &mut clock, 10_000));
+        assert_increasing(&parts);
+    }
+
+    #[test]
+    fn ids_differ_across_nodes() {
+        let nodes: Vec<Snowflake> = (0..=MAX_NODE)
+            .step_by(97)
+            .map(|n| Snowflake::new(n).unwrap())
+            .collect();
+        let mut seen = HashSet::new();
+        for ids in &nodes {
+            let mut clock = START_MS;
+            for parts in wander(ids, &mut clock, 10_000) {
+                assert!(seen.insert(parts.id()), "{:?}", parts);
+            }
+        }
+    }
+
+    #[test]
+    fn node_index_comes_from_the_node_id() {
+        assert_eq!(Snowflake::for_node("n3").unwrap().node, 3);
+        assert!(Snowflake::for_node("c3").is_none());
+        assert!(Snowflake::for_node("n1024").is_none());
+    }
+}
@@ -0,0 +1,22 @@
+//! Fixtures for testing nodes without Maelstrom. The binaries are separate
+//! crates, which don't see this library's `cfg(test)` items, so these are
+//! public like the rest of it.
+
+use maelstrom::{MembershipState, Runtime};
+
+/// The runtimes of `n` nodes, `n0` up to `n{n-1}`, each of which knows its
+/// own id and those of the others. They have no transport, so they do for
+/// code that reads the membership but not for code that sends messages.
+pub fn cluster(n: usize) -> Vec<Runtime> {
+    let ids: Vec<String> = (0..n).map(|i| format!("n{}", i)).collect();
+    let runtime = |id: &String| {
+        let runtime = Runtime::new();
+        let membership = MembershipState {
+            node_id: id.clone(),
+            nodes: ids.clone(),
+        };
+        runtime.set_membership_state(membership).unwrap();
+        runtime
+    };
+    ids.iter().map(runtime).collect()
+}
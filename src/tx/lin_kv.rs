@@ -0,0 +1,154 @@
+use crate::{Engine, Op, Value};
+use async_trait::async_trait;
+use futures::future::join_all;
+use gossip_glomers::kv::{retry, Kv, KvError, Result as KvResult};
+use maelstrom::kv::lin_kv;
+use maelstrom::{Error, Result, Runtime};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Runs transactions against lin-kv in three rounds of concurrent calls:
+///
+/// 1. It reads every key the transaction touches, once each, whatever the
+///    number of ops on it. Keys that are only written are read as well, so a
+///    failed write-back can put them back.
+/// 2. It runs the ops locally, each one seeing the writes of the ones
+///    before.
+/// 3. It writes back the final value of every key that changed, with a cas
+///    from the value it read, so a key another transaction wrote in between
+///    is left alone and the transaction aborts.
+///
+/// Writes land one key at a time, so a transaction whose write-back fails
+/// halfway has to undo the writes it made. The client hears:
+///
+/// - temporarily-unavailable (11) if a read failed, or the op's own error if
+///   an op failed, such as abort (14) for a `cas` that didn't match; nothing
+///   was written yet either way;
+/// - abort (14) if a key changed since it was read, or its writes failed,
+///   and they were all rolled back;
+/// - txn-conflict (30) if another transaction wrote over one of its writes,
+///   which is then left alone rather than clobbering the newer value;
+/// - crash (13) if a write-back timed out, since it may still land after the
+///   rollback;
+/// - whatever error the rollback ran into if it failed too, since the writes
+///   may or may not still be there.
+pub(crate) struct LinKv {
+    s: Kv,
+}
+
+impl LinKv {
+    pub(crate) fn new(runtime: Runtime) -> Self {
+        Self {
+            s: Kv::new(lin_kv(runtime)),
+        }
+    }
+
+    async fn read(&self, key: usize) -> KvResult<Option<Value>> {
+        self.s.get_or(key.to_string(), None).await
+    }
+
+    /// Puts back `prior`, the value `key` had before the transaction wrote
+    /// `written` to it, unless someone wrote over it since. Returns whether
+    /// the key holds `prior` again.
+    async fn undo(&self, key: usize, prior: &Option<Value>, written: &Value) -> KvResult<bool> {
+        retry(|| async {
+            let current = self.read(key).await?;
+            if current == *prior {
+                return Ok(true);
+            }
+            if current.as_ref() != Some(written) {
+                return Ok(false);
+            }
+            // The next attempt's read tells whether a cas we didn't hear back
+            // from went through.
+            let restored = self.s.cas(key.to_string(), current, prior.clone(), false);
+            restored.await.map_err(KvError::reread)?;
+            Ok(true)
+        })
+        .await
+    }
+
+    /// Undoes `writes`, given the values the keys had before in `prior`.
+    /// Returns the error to fail the transaction with.
+    async fn rollback(
+        &self,
+        prior: &BTreeMap<usize, Option<Value>>,
+        writes: &BTreeMap<usize, Value>,
+    ) -> Error {
+        let undone = join_all(
+            writes
+                .iter()
+                .map(|(key, written)| self.undo(*key, &prior[key], written)),
+        )
+        .await;
+        let mut overwritten = false;
+        for restored in undone {
+            match restored {
+                Ok(restored) => overwritten |= !restored,
+                Err(e) => return e.into(),
+            }
+        }
+        match overwritten {
+            true => Error::TxnConflict,
+            false => Error::Abort,
+        }
+    }
+}
+
+#[async_trait]
+impl Engine for LinKv {
+    async fn txn(&self, _runtime: &Runtime, txn: Vec<Op>) -> Result<Vec<Op>> {
+        let keys: BTreeSet<usize> = txn.iter().map(|op| op.1).collect();
+        let reads = join_all(keys.iter().map(|key| self.read(*key))).await;
+        let mut prior = BTreeMap::<usize, Option<Value>>::new();
+        for (key, value) in keys.into_iter().zip(reads) {
+            let Ok(value) = value else {
+                return Err(Box::new(Error::TemporarilyUnavailable));
+            };
+            prior.insert(key, value);
+        }
+
+        let mut result_txn: Vec<Op> = vec![];
+        let mut writes = BTreeMap::<usize, Value>::new();
+        for op in txn {
+            let current = writes.get(&op.1).or(prior[&op.1].as_ref());
+            let (op, write) = op.apply(current)?;
+            if let Some(value) = write {
+                writes.insert(op.1, value);
+            }
+            result_txn.push(op);
+        }
+
+        let written = join_all(writes.iter().map(|(key, value)| {
+            let from = prior[key].clone();
+            let create = from.is_none();
+            self.s
+                .cas(key.to_string(), from, Some(value.clone()), create)
+        }))
+        .await;
+        if written.iter().all(|written| written.is_ok()) {
+            return Ok(result_txn);
+        }
+        // A key that no longer held what we read was left alone; any other
+        // failure may or may not have landed.
+        let indefinite = written
+            .iter()
+            .any(|written| matches!(written, Err(KvError::Indefinite)));
+        let landed = writes
+            .into_iter()
+            .zip(written)
+            .filter(|(_, written)| {
+                !matches!(
+                    written,
+                    Err(KvError::PreconditionFailed | KvError::KeyDoesNotExist)
+                )
+            })
+            .map(|(write, _)| write)
+            .collect();
+        let error = self.rollback(&prior, &landed).await;
+        if indefinite {
+            // A cas we didn't hear back from may still land after the undo.
+            return Err(KvError::Indefinite.into());
+        }
+        Err(Box::new(error))
+    }
+}
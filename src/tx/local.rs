@@ -0,0 +1,757 @@
+//! Totally available transactions: every node runs the transactions it is
+//! sent against its own in-memory copy of the data, replies straight away and
+//! gossips the writes to its peers in the background.
+//!
+//! Every transaction is stamped with a hybrid logical clock reading. For each
+//! key, all nodes keep the write with the highest one, followed by the
+//! elements appended with a higher one still, in timestamp order. The writes
+//! to a key are therefore ordered the same way everywhere, by timestamp,
+//! which rules out dirty writes (G0) at either isolation level, and appends
+//! made concurrently on different nodes all make it into the list:
+//!
+//! - Under [`Isolation::ReadUncommitted`] writes land in the store as soon as
+//!   their op executes, so a transaction running concurrently on the same node
+//!   may read them before the writer is done, and they stay even if a later
+//!   op of the writer fails.
+//! - Under [`Isolation::ReadCommitted`] a transaction buffers its writes and
+//!   installs all of them at once when it commits, and peers install the
+//!   writes gossiped to them at once as well. Nothing but the final writes of
+//!   committed transactions is ever visible, so neither aborted (G1a) nor
+//!   intermediate (G1b) reads can happen.
+//! - Under [`Isolation::Snapshot`] a transaction additionally reads the store
+//!   as of the moment it began, and fails with txn-conflict if a key it wrote
+//!   got committed by someone else in the meantime. Each key has an owner,
+//!   `key % nodes`, which remembers the last transaction it let write the
+//!   key. Before committing, a transaction asks the owner of every key it
+//!   wrote whether that is still the one whose write it read, and takes the
+//!   key over if so; the first committer wins on every node alike. If any
+//!   owner says no, the keys taken over are handed back.
+
+use crate::mvcc::{Mvcc, Snapshot};
+use crate::{Engine, Op, OpType, Value};
+use async_trait::async_trait;
+use futures::future::join_all;
+use gossip_glomers::gossip::{Gossip, State as GossipState};
+use gossip_glomers::hlc::{Hlc, Timestamp};
+use maelstrom::protocol::Message;
+use maelstrom::{Error, Result, Runtime};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_context::context::Context;
+
+/// How long a node waits for an owner to answer.
+const RPC_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long a node first waits before asking an owner again to take back
+/// the keys of a transaction that failed, doubling up to `RELEASE_BACKOFF_MAX`.
+const RELEASE_BACKOFF: Duration = Duration::from_millis(50);
+
+const RELEASE_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Isolation {
+    ReadUncommitted,
+    ReadCommitted,
+    Snapshot,
+}
+
+#[derive(Clone)]
+pub(crate) struct Local {
+    isolation: Isolation,
+    clock: Arc<Hlc>,
+    inner: Arc<Mutex<State>>,
+    /// The writes gossiped to the other nodes.
+    writes: Gossip<Writes>,
+}
+
+impl Local {
+    pub(crate) fn new(isolation: Isolation) -> Self {
+        let clock = Arc::new(Hlc::new());
+        let inner = Arc::new(Mutex::new(State::new()));
+        let state = inner.clone();
+        let writes = Gossip::new()
+            .with_clock(clock.clone())
+            .on_merge(move |writes: &Writes| state.lock().unwrap().apply(writes));
+        Self {
+            isolation,
+            clock,
+            inner,
+            writes,
+        }
+    }
+
+    /// Hands the writes of a committed transaction to gossip, which sends
+    /// them on to every other node.
+    fn replicate(&self, writes: &Writes) {
+        self.writes.update(|gossiped| gossiped.merge(writes));
+    }
+
+    /// Sends `req` to the owner `node`, or handles it right here if this
+    /// node is the owner.
+    async fn call(&self, runtime: &Runtime, node: &str, req: Request) -> Result<Response> {
+        if node == runtime.node_id() {
+            return self.handle(req);
+        }
+        let (ctx, _handler) = Context::with_timeout(RPC_TIMEOUT);
+        let msg = runtime.call(ctx, node, self.clock.stamp(req)).await?;
+        self.clock.observe(&msg);
+        msg.body.as_obj()
+    }
+
+    /// The owner side of snapshot isolation.
+    fn handle(&self, req: Request) -> Result<Response> {
+        let mut state = self.inner.lock().unwrap();
+        match req {
+            Request::Validate { txn, seen } => match state.validate(&txn, &seen) {
+                true => Ok(Response::ValidateOk {}),
+                false => Err(Box::new(Error::TxnConflict)),
+            },
+            Request::Release { txn, seen } => {
+                state.release(&txn, &seen);
+                Ok(Response::ReleaseOk {})
+            }
+            Request::Init {} => Err(Box::new(Error::NotSupported("init".to_string()))),
+        }
+    }
+
+    /// Commits `writes` of `txn`, which read `snapshot`, once the owner of
+    /// every key agreed that its last writer is still the one `txn` saw, as
+    /// listed in `seen`, and handed the key over to `txn`. If any owner
+    /// refuses or doesn't answer, gives every key back and fails with
+    /// txn-conflict or temporarily-unavailable.
+    async fn commit(
+        &self,
+        runtime: &Runtime,
+        txn: &Ts,
+        snapshot: &Snapshot,
+        writes: &Writes,
+        seen: Seen,
+    ) -> Result<()> {
+        let nodes = runtime.nodes();
+        let mut owned = BTreeMap::<String, Seen>::new();
+        for (key, ts) in seen {
+            let owner = nodes[key % nodes.len()].clone();
+            owned.entry(owner).or_default().push((key, ts));
+        }
+        let replies = join_all(owned.iter().map(|(n, seen)| {
+            let req = Request::Validate {
+                txn: txn.clone(),
+                seen: seen.clone(),
+            };
+            self.call(runtime, n, req)
+        }))
+        .await;
+        let mut failure: Option<Error> = None;
+        for reply in replies {
+            if let Err(e) = reply {
+                failure = Some(match e.downcast_ref::<Error>() {
+                    Some(Error::TxnConflict) => Error::TxnConflict,
+                    _ => Error::TemporarilyUnavailable,
+                });
+            }
+        }
+        // The owners have the last word, so the store agrees with them
+        // unless a write went around them.
+        if failure.is_none() && !self.inner.lock().unwrap().commit(snapshot, writes) {
+            failure = Some(Error::TxnConflict);
+        }
+        let Some(e) = failure else {
+            return Ok(());
+        };
+        for (n, seen) in owned {
+            let txn = txn.clone();
+            self.release(runtime, n, Request::Release { txn, seen });
+        }
+        Err(Box::new(e))
+    }
+
+    /// Gives the keys of a failed transaction back to the owner `node`. A
+    /// key an owner never gets back can't be written again, so other owners
+    /// are asked in the background until they answer.
+    fn release(&self, runtime: &Runtime, node: String, req: Request) {
+        if node == runtime.node_id() {
+            let _ = self.handle(req);
+            return;
+        }
+        let (local, runtime) = (self.clone(), runtime.clone());
+        tokio::spawn(async move {
+            let mut backoff = RELEASE_BACKOFF;
+            while local.call(&runtime, &node, req.clone()).await.is_err() {
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(RELEASE_BACKOFF_MAX);
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl Engine for Local {
+    async fn txn(&self, runtime: &Runtime, txn: Vec<Op>) -> Result<Vec<Op>> {
+        let node = runtime.node_id().to_string();
+        let (ts, snapshot) = {
+            let mut state = self.inner.lock().unwrap();
+            let ts = Ts(self.clock.now(), node.clone());
+            (ts, state.store.begin())
+        };
+        let read = |own: &HashMap<usize, Value>, key: usize| match own.get(&key) {
+            Some(value) => Some(value.clone()),
+            None => {
+                let state = self.inner.lock().unwrap();
+                let version = match self.isolation {
+                    Isolation::Snapshot => state.store.read(&snapshot, key),
+                    _ => state.store.latest(key),
+                };
+                version.map(|(_, value)| value.clone())
+            }
+        };
+        let mut writes = Writes::default();
+        let execute = || -> Result<Vec<Op>> {
+            let mut result_txn: Vec<Op> = vec![];
+            let mut own = HashMap::<usize, Value>::new();
+            for op in txn {
+                let current = match op.0.reads() {
+                    true => read(&own, op.1),
+                    false => None,
+                };
+                let op_type = op.0;
+                let (op, write) = op.apply(current.as_ref())?;
+                if let Some(value) = write {
+                    if self.isolation == Isolation::ReadUncommitted {
+                        // Every op commits on its own, so it gets a
+                        // timestamp of its own.
+                        let ts = Ts(self.clock.now(), node.clone());
+                        let mut applied = Writes::default();
+                        applied.record(&op, op_type, &ts, value.clone());
+                        self.inner.lock().unwrap().apply(&applied);
+                        writes.merge(&applied);
+                    } else {
+                        writes.record(&op, op_type, &ts, value.clone());
+                    }
+                    own.insert(op.1, value);
+                }
+                result_txn.push(op);
+            }
+            Ok(result_txn)
+        };
+        let executed = execute();
+        let seen = match self.isolation {
+            Isolation::Snapshot => self.inner.lock().unwrap().seen(&snapshot, &writes),
+            _ => vec![],
+        };
+        let committed = match (executed, self.isolation) {
+            (Err(e), isolation) => {
+                // The ops that ran before the failing one are in the store
+                // already, so peers have to get them as well.
+                if isolation == Isolation::ReadUncommitted {
+                    self.replicate(&writes);
+                }
+                Err(e)
+            }
+            (Ok(result_txn), Isolation::ReadUncommitted) => Ok(result_txn),
+            (Ok(result_txn), Isolation::ReadCommitted) => {
+                self.inner.lock().unwrap().apply(&writes);
+                Ok(result_txn)
+            }
+            (Ok(result_txn), Isolation::Snapshot) => {
+                let committed = self.commit(runtime, &ts, &snapshot, &writes, seen).await;
+                committed.map(|()| result_txn)
+            }
+        };
+        self.inner.lock().unwrap().store.end(snapshot);
+        let result_txn = committed?;
+        self.replicate(&writes);
+        Ok(result_txn)
+    }
+
+    async fn process(&self, runtime: Runtime, req: Message) -> Result<()> {
+        let msg: Result<Request> = req.body.as_obj();
+        match msg {
+            Ok(Request::Init {}) => {
+                self.writes.start(runtime);
+                Ok(())
+            }
+            Ok(msg) => {
+                self.clock.observe(&req);
+                let response = self.handle(msg)?;
+                runtime.reply(req, self.clock.stamp(response)).await
+            }
+            Err(_) => self.writes.process(runtime, req).await,
+        }
+    }
+}
+
+/// A clock reading with the id of the node that took it, which totally
+/// orders the transactions of all nodes.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+struct Ts(Timestamp, String);
+
+/// What decides the value of a key: its write with the highest timestamp and
+/// the elements appended after it, by timestamp and then by their order
+/// within the transaction that appended them. Appends that come before the
+/// write don't count, as the write replaced them.
+#[serde_as]
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Register {
+    write: Option<(Ts, Value)>,
+    #[serde_as(as = "Vec<(_, _)>")]
+    appends: BTreeMap<(Ts, usize), Value>,
+}
+
+impl Register {
+    /// The value of the key, if it has one. Appends to a key whose write
+    /// isn't a list made no sense to begin with and are left out.
+    fn value(&self) -> Option<Value> {
+        let mut value = self.write.as_ref().map(|(_, value)| value.clone());
+        for elem in self.appends.values() {
+            value = match Value::append(value.as_ref(), elem) {
+                Ok(list) => Some(list),
+                Err(_) => break,
+            };
+        }
+        value
+    }
+
+    fn merge(&mut self, other: &Self) {
+        if let Some((ts, value)) = &other.write {
+            if !matches!(&self.write, Some((current, _)) if current >= ts) {
+                self.write = Some((ts.clone(), value.clone()));
+            }
+        }
+        for (at, elem) in &other.appends {
+            self.appends.insert(at.clone(), elem.clone());
+        }
+        if let Some((ts, _)) = &self.write {
+            self.appends.retain(|(at, _), _| at > ts);
+        }
+    }
+
+    fn diff(&self, since: &Self) -> Self {
+        let write = match (&self.write, &since.write) {
+            (Some((ts, _)), Some((seen, _))) if seen >= ts => None,
+            (write, _) => write.clone(),
+        };
+        let appends = self
+            .appends
+            .iter()
+            .filter(|(at, _)| !since.appends.contains_key(*at));
+        Self {
+            write,
+            appends: appends
+                .map(|(at, elem)| (at.clone(), elem.clone()))
+                .collect(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.write.is_none() && self.appends.is_empty()
+    }
+
+    /// The timestamp of the last transaction that wrote to the key.
+    fn ts(&self) -> Option<&Ts> {
+        let written = self.write.as_ref().map(|(ts, _)| ts);
+        let appended = self.appends.keys().next_back().map(|(ts, _)| ts);
+        written.max(appended)
+    }
+}
+
+/// The register of every key written.
+#[serde_as]
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Writes(#[serde_as(as = "Vec<(_, _)>")] HashMap<usize, Register>);
+
+impl Writes {
+    /// Adds the write `op` made, which left its key holding `value`, to the
+    /// writes of a transaction stamped with `ts`. An append goes in as such
+    /// unless the transaction wrote the key outright already, so that appends
+    /// made concurrently on other nodes aren't lost.
+    fn record(&mut self, op: &Op, op_type: OpType, ts: &Ts, value: Value) {
+        let register = self.0.entry(op.1).or_default();
+        match (op_type, &op.2) {
+            (OpType::Append, Some(elem)) if register.write.is_none() => {
+                let at = (ts.clone(), register.appends.len());
+                register.appends.insert(at, elem.clone());
+            }
+            _ => {
+                *register = Register {
+                    write: Some((ts.clone(), value)),
+                    appends: BTreeMap::new(),
+                }
+            }
+        }
+    }
+}
+
+impl GossipState for Writes {
+    fn merge(&mut self, other: &Self) {
+        for (key, register) in &other.0 {
+            self.0.entry(*key).or_default().merge(register);
+        }
+    }
+
+    fn diff(&self, since: &Self) -> Self {
+        let mut diff = HashMap::new();
+        for (key, register) in &self.0 {
+            let newer = match since.0.get(key) {
+                Some(seen) => register.diff(seen),
+                None => register.clone(),
+            };
+            if !newer.is_empty() {
+                diff.insert(*key, newer);
+            }
+        }
+        Writes(diff)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// The keys a transaction wrote, each with the timestamp of the last
+/// transaction that wrote to it as of the transaction's snapshot.
+type Seen = Vec<(usize, Option<Ts>)>;
+
+struct State {
+    /// The value of every key, with the timestamp of the last transaction
+    /// that wrote to it.
+    store: Mvcc<(Ts, Value)>,
+    /// The register of every key, which the store holds the values of.
+    registers: Writes,
+    /// For each key this node owns, the last transaction it let write the
+    /// key under snapshot isolation.
+    owned: HashMap<usize, Ts>,
+}
+
+impl State {
+    fn new() -> Self {
+        Self {
+            store: Mvcc::new(),
+            registers: Writes::default(),
+            owned: HashMap::new(),
+        }
+    }
+
+    /// The values the keys of `writes` get once they are merged in, leaving
+    /// out those that don't change.
+    fn values(&self, writes: &Writes) -> Vec<(usize, (Ts, Value))> {
+        let mut values = vec![];
+        for (key, written) in &writes.0 {
+            let current = self.registers.0.get(key);
+            let mut register = current.cloned().unwrap_or_default();
+            register.merge(written);
+            let version = match (register.ts(), register.value()) {
+                (Some(ts), Some(value)) => (ts.clone(), value),
+                _ => continue,
+            };
+            let unchanged = current.map(|c| (c.ts(), c.value()));
+            if unchanged != Some((Some(&version.0), Some(version.1.clone()))) {
+                values.push((*key, version));
+            }
+        }
+        values
+    }
+
+    /// Merges `writes`, from this node or gossiped from another, and
+    /// installs the new values of their keys as one commit.
+    fn apply(&mut self, writes: &Writes) {
+        let values = self.values(writes);
+        self.registers.merge(writes);
+        self.store.install(values);
+    }
+
+    /// Like [`State::apply`], unless one of the keys got a new version since
+    /// `snapshot` began.
+    fn commit(&mut self, snapshot: &Snapshot, writes: &Writes) -> bool {
+        let values = self.values(writes);
+        if self.store.commit(snapshot, values).is_err() {
+            return false;
+        }
+        self.registers.merge(writes);
+        true
+    }
+
+    /// The last writer of each key of `writes` as of `snapshot`.
+    fn seen(&self, snapshot: &Snapshot, writes: &Writes) -> Seen {
+        let seen = writes.0.keys().map(|key| {
+            let version = self.store.read(snapshot, *key);
+            (*key, version.map(|(ts, _)| ts.clone()))
+        });
+        seen.collect()
+    }
+
+    /// Hands the keys of `seen`, which this node owns, over to `txn`, unless
+    /// one of them was handed to someone else since `txn` read it.
+    fn validate(&mut self, txn: &Ts, seen: &Seen) -> bool {
+        if seen
+            .iter()
+            .any(|(key, ts)| self.owned.get(key) != ts.as_ref())
+        {
+            return false;
+        }
+        for (key, _) in seen {
+            self.owned.insert(*key, txn.clone());
+        }
+        true
+    }
+
+    /// Gives the keys of `seen` that `txn` took over back to their last
+    /// writer before it.
+    fn release(&mut self, txn: &Ts, seen: &Seen) {
+        for (key, ts) in seen {
+            if self.owned.get(key) != Some(txn) {
+                continue;
+            }
+            match ts {
+                Some(ts) => self.owned.insert(*key, ts.clone()),
+                None => self.owned.remove(key),
+            };
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum Request {
+    Init {},
+    Validate { txn: Ts, seen: Seen },
+    Release { txn: Ts, seen: Seen },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+#[allow(clippy::enum_variant_names)]
+enum Response {
+    ValidateOk {},
+    ReleaseOk {},
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use gossip_glomers::testing;
+
+    fn ts(ms: u64, node: &str) -> Ts {
+        Ts(Timestamp(ms, 0), node.to_string())
+    }
+
+    fn appended(ts: Ts, elems: &[usize]) -> Register {
+        let appends = elems
+            .iter()
+            .enumerate()
+            .map(|(i, elem)| ((ts.clone(), i), Value::Int(*elem)));
+        Register {
+            write: None,
+            appends: appends.collect(),
+        }
+    }
+
+    fn written(ts: Ts, value: Value) -> Register {
+        Register {
+            write: Some((ts, value)),
+            appends: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn concurrent_appends_all_land_in_timestamp_order() {
+        let a = appended(ts(2, "n0"), &[7, 9]);
+        let b = appended(ts(1, "n1"), &[8]);
+        let (mut ab, mut ba) = (a.clone(), b.clone());
+        ab.merge(&b);
+        ba.merge(&a);
+        let list = Value::List(vec![8, 7, 9]);
+        assert_eq!(ab.value(), Some(list.clone()));
+        assert_eq!(ba.value(), Some(list));
+    }
+
+    #[test]
+    fn a_write_replaces_the_appends_before_it() {
+        let mut register = appended(ts(1, "n0"), &[1]);
+        register.merge(&written(ts(2, "n1"), Value::List(vec![5])));
+        register.merge(&appended(ts(3, "n0"), &[6]));
+        assert_eq!(register.value(), Some(Value::List(vec![5, 6])));
+        assert_eq!(register.appends.len(), 1);
+    }
+
+    #[test]
+    fn diff_carries_only_what_is_missing() {
+        let mut seen = appended(ts(1, "n0"), &[1]);
+        let mut register = seen.clone();
+        register.merge(&appended(ts(2, "n1"), &[2]));
+        let diff = register.diff(&seen);
+        assert!(diff.write.is_none());
+        assert_eq!(diff.appends.len(), 1);
+        seen.merge(&diff);
+        assert_eq!(seen.value(), register.value());
+    }
+
+    #[test]
+    fn appends_to_a_register_are_left_out() {
+        let mut register = written(ts(1, "n0"), Value::Int(3));
+        register.merge(&appended(ts(2, "n1"), &[4]));
+        assert_eq!(register.value(), Some(Value::Int(3)));
+    }
+
+    struct Node {
+        runtime: Runtime,
+        engine: Local,
+    }
+
+    fn cluster(isolation: Isolation, n: usize) -> Vec<Node> {
+        let node = |runtime| {
+            let engine = Local::new(isolation);
+            Node { runtime, engine }
+        };
+        testing::cluster(n).into_iter().map(node).collect()
+    }
+
+    fn run(node: &Node, txn: &[Op]) -> Result<Vec<Op>> {
+        block_on(node.engine.txn(&node.runtime, txn.to_vec()))
+    }
+
+    fn read(node: &Node, key: usize) -> Option<Value> {
+        let read = run(node, &[Op(OpType::Read, key, None)]);
+        read.ok()?.remove(0).2
+    }
+
+    /// Runs a gossip round on every node in turn, delivering each diff.
+    fn gossip(nodes: &[Node]) {
+        let ids: Vec<String> = nodes.iter().map(|n| n.runtime.node_id().into()).collect();
+        for node in nodes {
+            let others: Vec<String> = node.runtime.neighbours().cloned().collect();
+            for (to, diff) in node.engine.writes.diffs(&others) {
+                let i = ids.iter().position(|id| *id == to).unwrap();
+                nodes[i]
+                    .engine
+                    .writes
+                    .receive(node.runtime.node_id(), &diff);
+                node.engine.writes.acked(&to, &diff, true);
+            }
+        }
+    }
+
+    fn w(key: usize, value: usize) -> Op {
+        Op(OpType::Write, key, Some(Value::Int(value)))
+    }
+
+    fn append(key: usize, elem: usize) -> Op {
+        Op(OpType::Append, key, Some(Value::Int(elem)))
+    }
+
+    fn cas(key: usize, from: usize, to: usize) -> Op {
+        Op(OpType::Cas, key, Some(Value::List(vec![from, to])))
+    }
+
+    #[test]
+    fn committed_writes_reach_other_nodes_together() {
+        let nodes = cluster(Isolation::ReadCommitted, 3);
+        run(&nodes[0], &[w(1, 1), w(2, 2)]).unwrap();
+        assert_eq!((read(&nodes[1], 1), read(&nodes[1], 2)), (None, None));
+        gossip(&nodes);
+        for node in &nodes {
+            let values = (read(node, 1), read(node, 2));
+            assert_eq!(values, (Some(Value::Int(1)), Some(Value::Int(2))));
+        }
+    }
+
+    #[test]
+    fn only_the_final_write_of_a_transaction_is_replicated() {
+        let nodes = cluster(Isolation::ReadCommitted, 2);
+        run(&nodes[0], &[w(1, 1), w(1, 2), append(2, 3), append(2, 4)]).unwrap();
+        let others = [nodes[1].runtime.node_id().to_string()];
+        let diffs = nodes[0].engine.writes.diffs(&others);
+        let (_, Writes(registers)) = &diffs[0];
+        assert_eq!(registers[&1].value(), Some(Value::Int(2)));
+        assert!(registers[&1].appends.is_empty());
+        assert_eq!(registers[&2].value(), Some(Value::List(vec![3, 4])));
+    }
+
+    #[test]
+    fn aborted_writes_are_never_seen() {
+        let nodes = cluster(Isolation::ReadCommitted, 2);
+        run(&nodes[0], &[w(3, 3)]).unwrap();
+        let aborted = run(&nodes[0], &[w(1, 1), append(2, 5), cas(3, 4, 5)]);
+        assert!(aborted.is_err());
+        gossip(&nodes);
+        for node in &nodes {
+            assert_eq!((read(node, 1), read(node, 2)), (None, None));
+            assert_eq!(read(node, 3), Some(Value::Int(3)));
+        }
+    }
+
+    #[test]
+    fn read_uncommitted_replicates_the_writes_before_a_failure() {
+        let nodes = cluster(Isolation::ReadUncommitted, 2);
+        let failed = run(&nodes[0], &[w(1, 1), cas(3, 4, 5)]);
+        assert!(failed.is_err());
+        gossip(&nodes);
+        for node in &nodes {
+            assert_eq!(read(node, 1), Some(Value::Int(1)));
+        }
+    }
+
+    #[test]
+    fn concurrent_appends_on_different_nodes_converge() {
+        let nodes = cluster(Isolation::ReadCommitted, 3);
+        run(&nodes[0], &[append(1, 7)]).unwrap();
+        run(&nodes[1], &[append(1, 8), append(1, 9)]).unwrap();
+        run(&nodes[2], &[append(1, 10)]).unwrap();
+        gossip(&nodes);
+        gossip(&nodes);
+        let list = read(&nodes[0], 1);
+        let Some(Value::List(elems)) = &list else {
+            panic!("{:?}", list);
+        };
+        assert_eq!(elems.len(), 4);
+        assert!(nodes.iter().all(|node| read(node, 1) == list));
+    }
+
+    #[test]
+    fn owners_let_only_the_first_writer_of_a_version_through() {
+        let mut owner = State::new();
+        let (a, b) = (ts(2, "n0"), ts(3, "n1"));
+        owner.owned.insert(4, ts(1, "n2"));
+        let seen = vec![(1, None), (4, Some(ts(1, "n2")))];
+        assert!(owner.validate(&a, &seen));
+        assert!(!owner.validate(&b, &seen));
+        // Once it has read what `a` wrote, `b` may write the key too.
+        assert!(owner.validate(&b, &vec![(1, Some(a))]));
+    }
+
+    #[test]
+    fn released_keys_go_back_to_their_last_writer() {
+        let mut owner = State::new();
+        let (a, b) = (ts(2, "n0"), ts(3, "n1"));
+        owner.owned.insert(4, ts(1, "n2"));
+        let seen = vec![(1, None), (4, Some(ts(1, "n2")))];
+        assert!(owner.validate(&a, &seen));
+        owner.release(&a, &seen);
+        assert_eq!(owner.owned.get(&1), None);
+        assert_eq!(owner.owned.get(&4), Some(&ts(1, "n2")));
+        assert!(owner.validate(&b, &seen));
+        // A release that comes late leaves the keys to whoever has them.
+        owner.release(&a, &seen);
+        assert_eq!(owner.owned.get(&1), Some(&b));
+    }
+
+    #[test]
+    fn snapshot_writers_read_the_last_writer_their_owner_knows() {
+        let nodes = cluster(Isolation::Snapshot, 2);
+        // Each node owns the key it writes, so no owner has to be called.
+        run(&nodes[0], &[w(0, 1)]).unwrap();
+        run(&nodes[0], &[append(2, 1), w(0, 2)]).unwrap();
+        gossip(&nodes);
+        run(&nodes[1], &[w(1, 3)]).unwrap();
+        run(&nodes[1], &[w(1, 4)]).unwrap();
+        gossip(&nodes);
+        for node in &nodes {
+            assert_eq!(read(node, 0), Some(Value::Int(2)));
+            assert_eq!(read(node, 1), Some(Value::Int(4)));
+            assert_eq!(read(node, 2), Some(Value::List(vec![1])));
+        }
+    }
+}
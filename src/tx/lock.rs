@@ -0,0 +1,226 @@
+//! Shared and exclusive locks on keys. Conflicts are settled wait-die: a
+//! transaction may only wait for locks held by younger transactions and dies
+//! instead of waiting for an older one, so no cycle of waiters can form.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// How long a transaction may hold locks before it has prepared. Past that,
+/// the first transaction to run into one of its locks takes them all away,
+/// so a coordinator that went quiet doesn't block its keys forever.
+const LOCK_HOLD: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Mode {
+    Shared,
+    Exclusive,
+}
+
+pub(crate) enum Acquire {
+    Granted,
+    /// Only younger transactions are in the way; try again once they let go.
+    Wait,
+    /// An older transaction is in the way.
+    Die,
+}
+
+/// Locks keyed by `usize`, held by transactions identified by `T`, which
+/// orders them from oldest to youngest.
+pub(crate) struct LockTable<T> {
+    locks: HashMap<usize, Lock<T>>,
+    held: HashMap<T, Held>,
+}
+
+struct Lock<T> {
+    mode: Mode,
+    holders: BTreeSet<T>,
+}
+
+struct Held {
+    keys: BTreeSet<usize>,
+    since: Instant,
+    prepared: bool,
+}
+
+impl<T: Ord + Hash + Clone> LockTable<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            locks: HashMap::new(),
+            held: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn try_acquire(&mut self, txn: &T, key: usize, mode: Mode) -> Acquire {
+        self.evict_expired(key);
+
+        let lock = self.locks.entry(key).or_insert_with(|| Lock {
+            mode,
+            holders: BTreeSet::new(),
+        });
+        let alone = lock.holders.iter().all(|holder| holder == txn);
+        if alone {
+            if lock.holders.is_empty() || mode == Mode::Exclusive {
+                lock.mode = mode;
+            }
+        } else if mode == Mode::Exclusive || lock.mode == Mode::Exclusive {
+            return match lock.holders.first() {
+                Some(oldest) if oldest < txn => Acquire::Die,
+                _ => Acquire::Wait,
+            };
+        }
+
+        lock.holders.insert(txn.clone());
+        let held = self.held.entry(txn.clone()).or_insert_with(|| Held {
+            keys: BTreeSet::new(),
+            since: Instant::now(),
+            prepared: false,
+        });
+        held.keys.insert(key);
+        Acquire::Granted
+    }
+
+    /// Whether `txn` holds a lock on `key` at least as strong as `mode`.
+    pub(crate) fn holds(&self, txn: &T, key: usize, mode: Mode) -> bool {
+        match self.locks.get(&key) {
+            Some(lock) => {
+                lock.holders.contains(txn) && (mode == Mode::Shared || lock.mode == Mode::Exclusive)
+            }
+            None => false,
+        }
+    }
+
+    /// Keeps the locks of `txn` from expiring, as it may commit any moment.
+    pub(crate) fn prepare(&mut self, txn: &T) {
+        if let Some(held) = self.held.get_mut(txn) {
+            held.prepared = true;
+        }
+    }
+
+    /// Lets go of every lock `txn` holds. Returns whether it held any.
+    pub(crate) fn release(&mut self, txn: &T) -> bool {
+        let Some(held) = self.held.remove(txn) else {
+            return false;
+        };
+        for key in held.keys {
+            if let Some(lock) = self.locks.get_mut(&key) {
+                lock.holders.remove(txn);
+                if lock.holders.is_empty() {
+                    self.locks.remove(&key);
+                }
+            }
+        }
+        true
+    }
+
+    fn evict_expired(&mut self, key: usize) {
+        let Some(lock) = self.locks.get(&key) else {
+            return;
+        };
+        let expired: Vec<T> = lock
+            .holders
+            .iter()
+            .filter(|holder| match self.held.get(*holder) {
+                Some(held) => !held.prepared && held.since.elapsed() >= LOCK_HOLD,
+                None => false,
+            })
+            .cloned()
+            .collect();
+        for txn in expired {
+            self.release(&txn);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl<T: Ord + Hash + Clone> LockTable<T> {
+        /// Makes the locks of `txn` look like they were taken `ago`.
+        pub(crate) fn backdate(&mut self, txn: &T, ago: Duration) {
+            let held = self.held.get_mut(txn).unwrap();
+            held.since = Instant::now().checked_sub(ago).unwrap();
+        }
+    }
+
+    #[test]
+    fn older_transactions_wait_and_younger_ones_die() {
+        let mut locks = LockTable::new();
+        assert!(matches!(
+            locks.try_acquire(&1, 0, Mode::Exclusive),
+            Acquire::Granted
+        ));
+        assert!(matches!(
+            locks.try_acquire(&5, 1, Mode::Shared),
+            Acquire::Granted
+        ));
+
+        assert!(matches!(
+            locks.try_acquire(&5, 0, Mode::Shared),
+            Acquire::Die
+        ));
+        assert!(matches!(
+            locks.try_acquire(&1, 1, Mode::Exclusive),
+            Acquire::Wait
+        ));
+        // Shared locks don't get in each other's way.
+        assert!(matches!(
+            locks.try_acquire(&1, 1, Mode::Shared),
+            Acquire::Granted
+        ));
+        assert!(locks.holds(&1, 1, Mode::Shared));
+        assert!(!locks.holds(&1, 1, Mode::Exclusive));
+
+        // Once the holder lets go, the lock is free for anyone.
+        assert!(locks.release(&1));
+        assert!(!locks.release(&1));
+        assert!(matches!(
+            locks.try_acquire(&5, 0, Mode::Exclusive),
+            Acquire::Granted
+        ));
+        assert!(locks.holds(&5, 0, Mode::Exclusive));
+    }
+
+    #[test]
+    fn expired_locks_are_taken_away() {
+        let mut locks = LockTable::new();
+        assert!(matches!(
+            locks.try_acquire(&1, 0, Mode::Shared),
+            Acquire::Granted
+        ));
+        assert!(matches!(
+            locks.try_acquire(&1, 1, Mode::Exclusive),
+            Acquire::Granted
+        ));
+        locks.backdate(&1, LOCK_HOLD);
+
+        assert!(matches!(
+            locks.try_acquire(&5, 0, Mode::Exclusive),
+            Acquire::Granted
+        ));
+        // All of them, not just the one in the way.
+        assert!(!locks.holds(&1, 0, Mode::Shared));
+        assert!(!locks.holds(&1, 1, Mode::Shared));
+        assert!(!locks.release(&1));
+    }
+
+    #[test]
+    fn prepared_locks_never_expire() {
+        let mut locks = LockTable::new();
+        assert!(matches!(
+            locks.try_acquire(&5, 0, Mode::Shared),
+            Acquire::Granted
+        ));
+        locks.prepare(&5);
+        locks.backdate(&5, LOCK_HOLD * 10);
+
+        assert!(matches!(
+            locks.try_acquire(&1, 0, Mode::Exclusive),
+            Acquire::Wait
+        ));
+        assert!(locks.holds(&5, 0, Mode::Shared));
+    }
+}
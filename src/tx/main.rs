@@ -0,0 +1,236 @@
+use async_trait::async_trait;
+use maelstrom::protocol::Message;
+use maelstrom::{done, Error, Node, Result, Runtime};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use std::env;
+use std::fmt::{Display, Formatter as FmtFormatter, Result as FmtResult};
+use std::result::Result as StdResult;
+use std::str::FromStr;
+use std::sync::Arc;
+
+mod lin_kv;
+mod local;
+mod lock;
+mod mvcc;
+mod occ;
+mod serializable;
+
+use lin_kv::LinKv;
+use local::{Isolation, Local};
+use occ::Occ;
+use serializable::Serializable;
+
+pub(crate) fn main() {
+    let _ = Runtime::init(try_main());
+}
+
+async fn try_main() -> Result<()> {
+    let runtime = Runtime::new();
+    let handler = Arc::new(Handler::new(runtime.clone())?);
+    runtime.with_handler(handler).run().await
+}
+
+/// How a node carries out the transactions it is sent.
+#[async_trait]
+trait Engine: Send + Sync {
+    /// Runs `txn` and returns its ops with the values read filled in. A
+    /// transaction that didn't commit fails with the `maelstrom::Error` the
+    /// client gets back, such as temporarily-unavailable (11), abort (14) or
+    /// txn-conflict (30).
+    async fn txn(&self, runtime: &Runtime, txn: Vec<Op>) -> Result<Vec<Op>>;
+
+    /// Handles every message that isn't a client transaction, such as the
+    /// ones the engine's nodes exchange among themselves.
+    async fn process(&self, runtime: Runtime, req: Message) -> Result<()> {
+        done(runtime, req)
+    }
+}
+
+#[derive(Clone)]
+struct Handler {
+    engine: Arc<dyn Engine>,
+}
+
+impl Handler {
+    /// Picks the engine named by the `TX_MODE` environment variable,
+    /// defaulting to `lin-kv`.
+    fn new(runtime: Runtime) -> Result<Self> {
+        let mode = env::var("TX_MODE").unwrap_or_else(|_| "lin-kv".to_string());
+        let engine: Arc<dyn Engine> = match mode.as_str() {
+            "lin-kv" => Arc::new(LinKv::new(runtime)),
+            "read-uncommitted" => Arc::new(Local::new(Isolation::ReadUncommitted)),
+            "read-committed" => Arc::new(Local::new(Isolation::ReadCommitted)),
+            "snapshot" => Arc::new(Local::new(Isolation::Snapshot)),
+            "serializable" => Arc::new(Serializable::new()),
+            "occ" => Arc::new(Occ::new(runtime)),
+            _ => return Err(format!("unknown TX_MODE {:?}", mode).into()),
+        };
+        Ok(Self { engine })
+    }
+}
+
+#[async_trait]
+impl Node for Handler {
+    async fn process(&self, runtime: Runtime, req: Message) -> Result<()> {
+        let msg: Result<Request> = req.body.as_obj();
+        if let Ok(Request::Txn { txn }) = msg {
+            let txn = self.engine.txn(&runtime, txn).await?;
+            let response = Response::TxnOk { txn };
+            return runtime.reply(req, response).await;
+        }
+        self.engine.process(runtime, req).await
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum OpType {
+    Read,
+    Write,
+    Append,
+    Cas,
+    Inc,
+}
+
+impl OpType {
+    /// Whether the op needs the current value of its key.
+    fn reads(self) -> bool {
+        self != OpType::Write
+    }
+
+    /// Whether the op may change the value of its key.
+    fn writes(self) -> bool {
+        self != OpType::Read
+    }
+}
+
+impl Display for OpType {
+    fn fmt(&self, f: &mut FmtFormatter) -> FmtResult {
+        match self {
+            OpType::Read => write!(f, "r"),
+            OpType::Write => write!(f, "w"),
+            OpType::Append => write!(f, "append"),
+            OpType::Cas => write!(f, "cas"),
+            OpType::Inc => write!(f, "inc"),
+        }
+    }
+}
+
+struct ParseOpTypeError;
+
+impl Display for ParseOpTypeError {
+    fn fmt(&self, f: &mut FmtFormatter) -> FmtResult {
+        write!(f, "Operation type error")
+    }
+}
+
+impl FromStr for OpType {
+    type Err = ParseOpTypeError;
+
+    fn from_str(s: &str) -> StdResult<Self, Self::Err> {
+        match s {
+            "r" => Ok(Self::Read),
+            "w" => Ok(Self::Write),
+            "append" => Ok(Self::Append),
+            "cas" => Ok(Self::Cas),
+            "inc" => Ok(Self::Inc),
+            _ => Err(ParseOpTypeError),
+        }
+    }
+}
+
+/// One op of a transaction, as `[type, key, value]`:
+///
+/// - `["r", k, null]` reads `k`, completing with its value;
+/// - `["w", k, v]` sets `k` to `v`;
+/// - `["append", k, v]` appends `v` to the list at `k`;
+/// - `["cas", k, [from, to]]` sets `k` to `to` if it holds `from`, and aborts
+///   the transaction otherwise;
+/// - `["inc", k, d]` adds `d` to the counter at `k`, completing with its new
+///   value. A key that was never written counts from 0.
+#[serde_as]
+#[derive(Clone, Serialize, Deserialize)]
+struct Op(
+    #[serde_as(as = "DisplayFromStr")] OpType,
+    usize,
+    Option<Value>,
+);
+
+impl Op {
+    /// Runs the op against `current`, the value its key holds. Returns the
+    /// op as it completed and the value to write to the key, if any. Fails
+    /// with abort if a `cas` doesn't match or an `inc` would overflow the
+    /// counter, or with malformed-request if the op's value or the key's
+    /// doesn't suit the op; either way the whole transaction has to be
+    /// abandoned.
+    fn apply(self, current: Option<&Value>) -> Result<(Op, Option<Value>)> {
+        let Op(op_type, key, value) = self;
+        let (value, write) = match (op_type, value) {
+            (OpType::Read, _) => (current.cloned(), None),
+            (OpType::Write, value) => (value.clone(), value),
+            (OpType::Append, Some(elem)) => {
+                let list = Value::append(current, &elem)?;
+                (Some(elem), Some(list))
+            }
+            (OpType::Cas, Some(Value::List(cas))) if cas.len() == 2 => {
+                if current != Some(&Value::Int(cas[0])) {
+                    return Err(Box::new(Error::Abort));
+                }
+                let to = Value::Int(cas[1]);
+                (Some(Value::List(cas)), Some(to))
+            }
+            (OpType::Inc, Some(Value::Int(delta))) => {
+                let sum = match current {
+                    Some(Value::Int(n)) => match n.checked_add(delta) {
+                        Some(sum) => Value::Int(sum),
+                        None => return Err(Box::new(Error::Abort)),
+                    },
+                    None => Value::Int(delta),
+                    Some(Value::List(_)) => return Err(Box::new(Error::MalformedRequest)),
+                };
+                (Some(sum.clone()), Some(sum))
+            }
+            _ => return Err(Box::new(Error::MalformedRequest)),
+        };
+        Ok((Op(op_type, key, value), write))
+    }
+}
+
+/// What a key holds: a register for `r`/`w` workloads, a list for `append`
+/// ones.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Value {
+    Int(usize),
+    List(Vec<usize>),
+}
+
+impl Value {
+    /// The value of a key holding `current` after `elem` got appended to it.
+    /// A key without a value starts a list; one holding a register can't be
+    /// appended to.
+    fn append(current: Option<&Value>, elem: &Value) -> Result<Value> {
+        let mut list = match current {
+            Some(Value::List(list)) => list.clone(),
+            None => vec![],
+            Some(Value::Int(_)) => return Err(Box::new(Error::MalformedRequest)),
+        };
+        match elem {
+            Value::Int(elem) => list.push(*elem),
+            Value::List(elems) => list.extend(elems),
+        }
+        Ok(Value::List(list))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum Request {
+    Txn { txn: Vec<Op> },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum Response {
+    TxnOk { txn: Vec<Op> },
+}
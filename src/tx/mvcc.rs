@@ -0,0 +1,184 @@
+//! An in-memory multi-version store. Every commit gets the next sequence
+//! number and adds a version stamped with it to each key it writes, so a
+//! transaction can keep reading the data as of the moment it began while
+//! others commit.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+pub(crate) struct Mvcc<V> {
+    /// Sequence number of the latest commit.
+    seq: u64,
+    /// Versions of each key, oldest first.
+    versions: HashMap<usize, Vec<(u64, V)>>,
+    /// Sequence numbers open snapshots read at, with how many of them read
+    /// at each.
+    active: BTreeMap<u64, usize>,
+    /// Keys holding more than one version, the only ones GC has work on.
+    stale: HashSet<usize>,
+}
+
+/// The state of the store as of one commit.
+pub(crate) struct Snapshot {
+    seq: u64,
+}
+
+/// Another transaction committed a write to a key since the snapshot began.
+pub(crate) struct Conflict;
+
+impl<V> Mvcc<V> {
+    pub(crate) fn new() -> Self {
+        Self {
+            seq: 0,
+            versions: HashMap::new(),
+            active: BTreeMap::new(),
+            stale: HashSet::new(),
+        }
+    }
+
+    /// Opens a snapshot of everything committed so far. It has to be handed
+    /// back with [`Mvcc::end`] so its versions can be collected.
+    pub(crate) fn begin(&mut self) -> Snapshot {
+        *self.active.entry(self.seq).or_default() += 1;
+        Snapshot { seq: self.seq }
+    }
+
+    pub(crate) fn end(&mut self, snapshot: Snapshot) {
+        if let Some(count) = self.active.get_mut(&snapshot.seq) {
+            *count -= 1;
+            if *count == 0 {
+                self.active.remove(&snapshot.seq);
+                self.gc();
+            }
+        }
+    }
+
+    /// The value of `key` as of `snapshot`.
+    pub(crate) fn read(&self, snapshot: &Snapshot, key: usize) -> Option<&V> {
+        let versions = self.versions.get(&key)?;
+        let visible = versions.iter().rev().find(|(seq, _)| *seq <= snapshot.seq);
+        visible.map(|(_, value)| value)
+    }
+
+    /// The most recently committed value of `key`.
+    pub(crate) fn latest(&self, key: usize) -> Option<&V> {
+        let versions = self.versions.get(&key)?;
+        versions.last().map(|(_, value)| value)
+    }
+
+    /// Commits `writes` on behalf of a transaction that read `snapshot`,
+    /// unless one of the keys got a newer version in the meantime: the first
+    /// committer wins and the others have to abort.
+    pub(crate) fn commit(
+        &mut self,
+        snapshot: &Snapshot,
+        writes: Vec<(usize, V)>,
+    ) -> Result<(), Conflict> {
+        let conflict = writes.iter().any(|(key, _)| {
+            let versions = self.versions.get(key);
+            let last = versions.and_then(|versions| versions.last());
+            matches!(last, Some((seq, _)) if *seq > snapshot.seq)
+        });
+        if conflict {
+            return Err(Conflict);
+        }
+        self.install(writes);
+        Ok(())
+    }
+
+    /// Commits `writes` without checking them for conflicts.
+    pub(crate) fn install(&mut self, writes: Vec<(usize, V)>) {
+        if writes.is_empty() {
+            return;
+        }
+        self.seq += 1;
+        for (key, value) in writes {
+            let versions = self.versions.entry(key).or_default();
+            match versions.last_mut() {
+                // A transaction writing a key twice leaves only its last write.
+                Some((seq, last)) if *seq == self.seq => *last = value,
+                _ => versions.push((self.seq, value)),
+            }
+            if versions.len() > 1 {
+                self.stale.insert(key);
+            }
+        }
+        if self.active.is_empty() {
+            self.gc();
+        }
+    }
+
+    /// Drops every version no open snapshot can read anymore: all but the
+    /// newest of those committed at or before the oldest snapshot.
+    fn gc(&mut self) {
+        let horizon = self.active.keys().next().copied().unwrap_or(self.seq);
+        let versions = &mut self.versions;
+        self.stale.retain(|key| {
+            let Some(versions) = versions.get_mut(key) else {
+                return false;
+            };
+            let visible = versions.iter().rposition(|(seq, _)| *seq <= horizon);
+            if let Some(oldest) = visible {
+                versions.drain(..oldest);
+            }
+            versions.len() > 1
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_first_committer_wins() {
+        let mut store = Mvcc::new();
+        store.install(vec![(1, "a"), (2, "b")]);
+        let (first, second) = (store.begin(), store.begin());
+        assert!(store.commit(&first, vec![(1, "c")]).is_ok());
+        assert!(store.commit(&second, vec![(2, "d"), (1, "e")]).is_err());
+        // A conflict writes nothing at all.
+        assert_eq!(store.latest(2), Some(&"b"));
+        assert!(store.commit(&second, vec![(2, "d")]).is_ok());
+        assert_eq!(store.read(&second, 1), Some(&"a"));
+        assert_eq!(store.latest(1), Some(&"c"));
+        store.end(first);
+        store.end(second);
+    }
+
+    #[test]
+    fn gc_keeps_the_newest_version_at_or_below_the_oldest_snapshot() {
+        let mut store = Mvcc::new();
+        store.install(vec![(1, 1)]);
+        store.install(vec![(1, 2)]);
+        let old = store.begin();
+        store.install(vec![(1, 3)]);
+        let newer = store.begin();
+        store.install(vec![(1, 4)]);
+        store.install(vec![(1, 5)]);
+        assert_eq!(store.versions[&1].len(), 4);
+        assert_eq!(store.read(&old, 1), Some(&2));
+
+        store.end(old);
+        assert_eq!(store.versions[&1].len(), 3);
+        assert_eq!(store.read(&newer, 1), Some(&3));
+
+        store.end(newer);
+        assert_eq!(store.versions[&1], vec![(5, 5)]);
+        assert!(store.stale.is_empty());
+    }
+
+    #[test]
+    fn snapshots_read_what_was_committed_when_they_began() {
+        let mut store = Mvcc::new();
+        let empty = store.begin();
+        store.install(vec![(1, 1)]);
+        let one = store.begin();
+        store.install(vec![(1, 2), (2, 2)]);
+        assert_eq!(store.read(&empty, 1), None);
+        assert_eq!(store.read(&one, 1), Some(&1));
+        assert_eq!(store.read(&one, 2), None);
+        assert_eq!(store.latest(2), Some(&2));
+        store.end(empty);
+        store.end(one);
+    }
+}
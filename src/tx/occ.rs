@@ -0,0 +1,265 @@
+//! Optimistic transactions over one lin-kv root. The whole database is an
+//! immutable tree from keys to thunks, and the id of the current map sits
+//! under a single lin-kv key:
+//!
+//! - A thunk holds one value, a bucket holds the thunk of every key that
+//!   hashes to it, and a map holds the id of every bucket. All of them are
+//!   written once under a fresh id and never change, so any node may cache
+//!   them, and a new map shares the buckets it didn't write, and the thunks of
+//!   all keys it didn't write, with the map it was made from.
+//! - A transaction reads the root, runs its ops against the map it points to,
+//!   writes thunks for its writes, the buckets they land in and a new map,
+//!   then swings the root from the map it read to the new one with a single
+//!   CAS.
+//!
+//! The root only moves when nobody committed since the transaction read it,
+//! so transactions take effect in root order, at their CAS. That is strict
+//! serializability, with no locks. A transaction that loses the race runs
+//! again on the newer map, a few times, before failing with txn-conflict.
+
+use crate::{Engine, Op, Value};
+use async_trait::async_trait;
+use gossip_glomers::hlc::Hlc;
+use gossip_glomers::kv::{Kv, KvError, Result as KvResult};
+use maelstrom::kv::lin_kv;
+use maelstrom::{Error, Result, Runtime};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// The lin-kv key holding the id of the current map.
+const ROOT: &str = "root";
+
+/// How many times a transaction runs again after losing the root CAS.
+const COMMIT_RETRIES: usize = 3;
+
+/// Number of buckets keys are spread over. A commit rewrites the map, which
+/// names every bucket, and each bucket it writes to.
+const BUCKETS: usize = 16;
+
+/// Number of the latest maps whose buckets and thunks stay cached.
+const CACHED_MAPS: usize = 8;
+
+/// The id of every bucket, by `key % BUCKETS`. Buckets nothing was written to
+/// yet have an empty id.
+type Map = Vec<String>;
+
+/// The id of the thunk of every key in a bucket.
+type Bucket = BTreeMap<usize, String>;
+
+pub(crate) struct Occ {
+    s: Kv,
+    /// Names thunks and maps, so no id comes up twice, even across restarts.
+    clock: Hlc,
+    inner: Mutex<Cache>,
+}
+
+/// Maps, buckets and thunks already read or written, by id. Only what the
+/// latest `CACHED_MAPS` maps reach is kept.
+#[derive(Default)]
+struct Cache {
+    maps: HashMap<String, Arc<Map>>,
+    buckets: HashMap<String, Arc<Bucket>>,
+    thunks: HashMap<String, Value>,
+    /// The ids of the cached maps, oldest first.
+    recent: VecDeque<String>,
+}
+
+impl Cache {
+    /// Caches `map`, dropping the oldest map once there are too many, along
+    /// with the buckets and thunks no other cached map reaches.
+    fn insert_map(&mut self, id: String, map: Arc<Map>) {
+        if self.maps.insert(id.clone(), map).is_some() {
+            return;
+        }
+        self.recent.push_back(id);
+        if self.recent.len() <= CACHED_MAPS {
+            return;
+        }
+        if let Some(oldest) = self.recent.pop_front() {
+            self.maps.remove(&oldest);
+        }
+        let buckets: HashSet<&String> = self.maps.values().flat_map(|m| m.iter()).collect();
+        self.buckets.retain(|id, _| buckets.contains(id));
+        let thunks: HashSet<&String> = self.buckets.values().flat_map(|b| b.values()).collect();
+        self.thunks.retain(|id, _| thunks.contains(id));
+    }
+}
+
+impl Occ {
+    pub(crate) fn new(runtime: Runtime) -> Self {
+        Self {
+            s: Kv::new(lin_kv(runtime)),
+            clock: Hlc::new(),
+            inner: Mutex::new(Cache::default()),
+        }
+    }
+
+    fn next_id(&self, node: &str) -> String {
+        format!("{}-{}", node, self.clock.now())
+    }
+
+    async fn map(&self, id: &str) -> KvResult<Arc<Map>> {
+        if id.is_empty() {
+            return Ok(Arc::new(vec![String::new(); BUCKETS]));
+        }
+        if let Some(map) = self.inner.lock().unwrap().maps.get(id) {
+            return Ok(map.clone());
+        }
+        let map: Arc<Map> = Arc::new(self.s.get(format!("map-{}", id)).await?);
+        let mut cache = self.inner.lock().unwrap();
+        cache.insert_map(id.to_string(), map.clone());
+        Ok(map)
+    }
+
+    async fn bucket(&self, id: &str) -> KvResult<Arc<Bucket>> {
+        if id.is_empty() {
+            return Ok(Arc::default());
+        }
+        if let Some(bucket) = self.inner.lock().unwrap().buckets.get(id) {
+            return Ok(bucket.clone());
+        }
+        let entries: Vec<(usize, String)> = self.s.get(format!("bucket-{}", id)).await?;
+        let bucket = Arc::new(entries.into_iter().collect::<Bucket>());
+        let mut cache = self.inner.lock().unwrap();
+        cache.buckets.insert(id.to_string(), bucket.clone());
+        Ok(bucket)
+    }
+
+    async fn thunk(&self, id: &str) -> KvResult<Value> {
+        if let Some(value) = self.inner.lock().unwrap().thunks.get(id) {
+            return Ok(value.clone());
+        }
+        let value: Value = self.s.get(format!("thunk-{}", id)).await?;
+        self.inner
+            .lock()
+            .unwrap()
+            .thunks
+            .insert(id.to_string(), value.clone());
+        Ok(value)
+    }
+
+    /// Runs `txn` once against the current root. Returns the ops together
+    /// with the root it read and, if it wrote anything, the new map. Nothing
+    /// anyone can see changes until the root does, so a KV failure here is
+    /// temporarily-unavailable.
+    async fn attempt(&self, node: &str, txn: &[Op]) -> Result<(Vec<Op>, String, Option<Map>)> {
+        let unavailable = |_| Error::TemporarilyUnavailable;
+        let root = self.s.get_or(ROOT.to_string(), String::new()).await;
+        let root: String = root.map_err(unavailable)?;
+        let map = self.map(&root).await.map_err(unavailable)?;
+
+        let mut result_txn: Vec<Op> = vec![];
+        let mut own = HashMap::<usize, Value>::new();
+        for op in txn {
+            let current = match own.get(&op.1) {
+                Some(value) => Some(value.clone()),
+                None if op.0.reads() => {
+                    let bucket = self.bucket(&map[op.1 % BUCKETS]).await;
+                    match bucket.map_err(unavailable)?.get(&op.1) {
+                        Some(thunk) => Some(self.thunk(thunk).await.map_err(unavailable)?),
+                        None => None,
+                    }
+                }
+                None => None,
+            };
+            let (op, write) = op.clone().apply(current.as_ref())?;
+            if let Some(value) = write {
+                own.insert(op.1, value);
+            }
+            result_txn.push(op);
+        }
+        if own.is_empty() {
+            return Ok((result_txn, root, None));
+        }
+
+        let mut writes = BTreeMap::<usize, Vec<(usize, Value)>>::new();
+        for (key, value) in own {
+            writes.entry(key % BUCKETS).or_default().push((key, value));
+        }
+        let mut next = (*map).clone();
+        for (i, writes) in writes {
+            let mut bucket = (*self.bucket(&map[i]).await.map_err(unavailable)?).clone();
+            for (key, value) in writes {
+                let id = self.next_id(node);
+                let put = self.s.put(format!("thunk-{}", id), value.clone()).await;
+                put.map_err(unavailable)?;
+                self.inner.lock().unwrap().thunks.insert(id.clone(), value);
+                bucket.insert(key, id);
+            }
+            let id = self.next_id(node);
+            let entries: Vec<(usize, String)> = bucket.clone().into_iter().collect();
+            let put = self.s.put(format!("bucket-{}", id), entries).await;
+            put.map_err(unavailable)?;
+            let bucket = Arc::new(bucket);
+            self.inner
+                .lock()
+                .unwrap()
+                .buckets
+                .insert(id.clone(), bucket);
+            next[i] = id;
+        }
+        Ok((result_txn, root, Some(next)))
+    }
+}
+
+#[async_trait]
+impl Engine for Occ {
+    async fn txn(&self, runtime: &Runtime, txn: Vec<Op>) -> Result<Vec<Op>> {
+        for _ in 0..=COMMIT_RETRIES {
+            let (result_txn, root, next) = self.attempt(runtime.node_id(), &txn).await?;
+            // A read-only transaction takes effect when it reads the root.
+            let Some(next) = next else {
+                return Ok(result_txn);
+            };
+
+            let id = self.next_id(runtime.node_id());
+            if self
+                .s
+                .put(format!("map-{}", id), next.clone())
+                .await
+                .is_err()
+            {
+                return Err(Box::new(Error::TemporarilyUnavailable));
+            }
+            let map = Arc::new(next);
+            self.inner.lock().unwrap().insert_map(id.clone(), map);
+
+            let create = root.is_empty();
+            match self.s.cas(ROOT.to_string(), root, id, create).await {
+                Ok(()) => return Ok(result_txn),
+                Err(KvError::PreconditionFailed) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(Box::new(Error::TxnConflict))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_cache_keeps_only_what_the_latest_maps_reach() {
+        let mut cache = Cache::default();
+        for i in 0..CACHED_MAPS + 2 {
+            let (bucket, thunk) = (format!("b{}", i), format!("t{}", i));
+            cache.thunks.insert(thunk.clone(), Value::Int(i));
+            let entries = Bucket::from([(i, thunk)]);
+            cache.buckets.insert(bucket.clone(), Arc::new(entries));
+            // Every map still reaches the first bucket and its thunk.
+            let mut map = vec![String::new(); BUCKETS];
+            map[0] = "b0".to_string();
+            map[1] = bucket;
+            cache.insert_map(format!("m{}", i), Arc::new(map));
+        }
+        assert_eq!(cache.maps.len(), CACHED_MAPS);
+        assert!(!cache.maps.contains_key("m1"));
+        assert!(cache.maps.contains_key("m2"));
+        assert!(!cache.buckets.contains_key("b1"));
+        assert!(!cache.thunks.contains_key("t1"));
+        assert!(cache.buckets.contains_key("b0"));
+        assert!(cache.thunks.contains_key("t0"));
+        assert_eq!(cache.thunks.len(), CACHED_MAPS + 1);
+    }
+}
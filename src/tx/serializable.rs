@@ -0,0 +1,432 @@
+//! Serializable transactions by strict two-phase locking and two-phase
+//! commit. Every key is owned by one node, `key % nodes`, which keeps its
+//! value and its lock. The node a client picks coordinates the transaction:
+//!
+//! 1. It locks every key the transaction touches on the owning nodes, shared
+//!    for keys it only reads and exclusive for keys it writes, and gets their
+//!    values back.
+//! 2. It runs the ops against those values.
+//! 3. It sends every owner the locks it took there and the final values of
+//!    its keys to prepare. An owner prepares only if the transaction still
+//!    holds all of those locks, shared ones included, as they may have
+//!    expired in the meantime. Once all of them are prepared it tells every
+//!    owner to commit, which installs the writes and releases the locks.
+//!
+//! A transaction that would have to wait for an older one, or waits too long,
+//! gives up with txn-conflict and all its locks are released.
+
+use crate::lock::{Acquire, LockTable, Mode};
+use crate::{Engine, Op, Value};
+use async_trait::async_trait;
+use futures::future::join_all;
+use gossip_glomers::hlc::{Hlc, Timestamp};
+use maelstrom::protocol::Message;
+use maelstrom::{done, Error, Result, Runtime};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::pin::pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::{timeout, Instant};
+use tokio_context::context::Context;
+
+/// How long a transaction waits for a lock before giving up. Shorter than
+/// `RPC_TIMEOUT`, so the coordinator hears about it.
+const LOCK_WAIT: Duration = Duration::from_millis(300);
+
+/// How long a lock waiter sleeps before checking again, in case the holder's
+/// locks expired rather than got released.
+const LOCK_POLL: Duration = Duration::from_millis(50);
+
+/// How long the coordinator waits for an owner to answer.
+const RPC_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long the coordinator first waits before telling an owner again how a
+/// transaction ended, doubling up to `FINISH_BACKOFF_MAX`.
+const FINISH_BACKOFF: Duration = Duration::from_millis(50);
+
+const FINISH_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
+pub(crate) struct Serializable {
+    clock: Arc<Hlc>,
+    inner: Arc<Mutex<State>>,
+    released: Arc<Notify>,
+}
+
+impl Serializable {
+    pub(crate) fn new() -> Self {
+        Self {
+            clock: Arc::new(Hlc::new()),
+            inner: Arc::new(Mutex::new(State::new())),
+            released: Arc::new(Notify::new()),
+        }
+    }
+
+    fn next_id(&self, node: &str) -> TxnId {
+        TxnId(self.clock.now(), node.to_string())
+    }
+
+    /// Sends `req` to the owner `node`, or handles it right here if this
+    /// node is the owner.
+    async fn call(&self, runtime: &Runtime, node: &str, req: Request) -> Result<Response> {
+        if node == runtime.node_id() {
+            return self.handle(req).await;
+        }
+        let (ctx, _handler) = Context::with_timeout(RPC_TIMEOUT);
+        let msg = runtime.call(ctx, node, self.clock.stamp(req)).await?;
+        self.clock.observe(&msg);
+        msg.body.as_obj()
+    }
+
+    /// Sends `req` to every owner in `nodes` at once.
+    async fn broadcast<'a>(
+        &self,
+        runtime: &Runtime,
+        nodes: impl Iterator<Item = &'a String>,
+        req: impl Fn(&'a String) -> Request,
+    ) -> Vec<Result<Response>> {
+        join_all(nodes.map(|n| self.call(runtime, n, req(n)))).await
+    }
+
+    /// Tells every owner the transaction is over. This node hears it right
+    /// away; the others are told in the background until each of them has
+    /// heard it, since an owner that misses a commit would never install the
+    /// writes nor release the locks. The client can have its answer in the
+    /// meantime: the locks of the transaction are held until the owner hears
+    /// the outcome, so nobody can read around it.
+    async fn finish(&self, runtime: &Runtime, txn: &TxnId, owners: &[String], commit: bool) {
+        let req = match commit {
+            true => Request::Commit { txn: txn.clone() },
+            false => Request::Abort { txn: txn.clone() },
+        };
+        for node in owners.iter().cloned() {
+            if node == runtime.node_id() {
+                let _ = self.handle(req.clone()).await;
+                continue;
+            }
+            let (serializable, runtime, req) = (self.clone(), runtime.clone(), req.clone());
+            tokio::spawn(async move {
+                let mut backoff = FINISH_BACKOFF;
+                while serializable
+                    .call(&runtime, &node, req.clone())
+                    .await
+                    .is_err()
+                {
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(FINISH_BACKOFF_MAX);
+                }
+            });
+        }
+    }
+
+    /// The participant side of the protocol.
+    async fn handle(&self, req: Request) -> Result<Response> {
+        match req {
+            Request::Lock { txn, locks } => {
+                let values = self.acquire(&txn, locks).await?;
+                Ok(Response::LockOk { values })
+            }
+            Request::Prepare { txn, locks, writes } => {
+                let mut state = self.inner.lock().unwrap();
+                let locked = locks
+                    .iter()
+                    .all(|(key, mode)| state.locks.holds(&txn, *key, *mode));
+                if !locked {
+                    // The locks expired while the coordinator was busy.
+                    return Err(Box::new(Error::Abort));
+                }
+                state.locks.prepare(&txn);
+                state.staged.insert(txn, writes);
+                Ok(Response::PrepareOk {})
+            }
+            Request::Commit { txn } => {
+                {
+                    let mut state = self.inner.lock().unwrap();
+                    if let Some(writes) = state.staged.remove(&txn) {
+                        state.data.extend(writes);
+                    }
+                    state.locks.release(&txn);
+                }
+                self.released.notify_waiters();
+                Ok(Response::CommitOk {})
+            }
+            Request::Abort { txn } => {
+                self.release(&txn);
+                Ok(Response::AbortOk {})
+            }
+        }
+    }
+
+    /// Takes `locks` for `txn` in key order and returns the values of the
+    /// keys. Gives up with txn-conflict, releasing whatever it got, as soon
+    /// as an older transaction is in the way or `LOCK_WAIT` runs out.
+    async fn acquire(
+        &self,
+        txn: &TxnId,
+        locks: Vec<(usize, Mode)>,
+    ) -> Result<Vec<(usize, Option<Value>)>> {
+        let deadline = Instant::now() + LOCK_WAIT;
+        for (key, mode) in &locks {
+            loop {
+                let mut notified = pin!(self.released.notified());
+                notified.as_mut().enable();
+                let acquired = self
+                    .inner
+                    .lock()
+                    .unwrap()
+                    .locks
+                    .try_acquire(txn, *key, *mode);
+                match acquired {
+                    Acquire::Granted => break,
+                    Acquire::Die => {
+                        self.release(txn);
+                        return Err(Box::new(Error::TxnConflict));
+                    }
+                    Acquire::Wait if Instant::now() >= deadline => {
+                        self.release(txn);
+                        return Err(Box::new(Error::TxnConflict));
+                    }
+                    Acquire::Wait => {
+                        let _ = timeout(LOCK_POLL, notified).await;
+                    }
+                }
+            }
+        }
+        let state = self.inner.lock().unwrap();
+        let values = locks
+            .iter()
+            .map(|(key, _)| (*key, state.data.get(key).cloned()));
+        Ok(values.collect())
+    }
+
+    fn release(&self, txn: &TxnId) {
+        let released = {
+            let mut state = self.inner.lock().unwrap();
+            state.staged.remove(txn);
+            state.locks.release(txn)
+        };
+        if released {
+            self.released.notify_waiters();
+        }
+    }
+}
+
+#[async_trait]
+impl Engine for Serializable {
+    async fn txn(&self, runtime: &Runtime, txn: Vec<Op>) -> Result<Vec<Op>> {
+        let id = self.next_id(runtime.node_id());
+        let nodes = runtime.nodes();
+        let owner = |key: usize| nodes[key % nodes.len()].clone();
+
+        let mut locks = BTreeMap::<String, BTreeMap<usize, Mode>>::new();
+        for Op(op_type, key, _) in &txn {
+            let mode = match op_type.writes() {
+                true => Mode::Exclusive,
+                false => Mode::Shared,
+            };
+            let owned = locks.entry(owner(*key)).or_default();
+            let current = owned.entry(*key).or_insert(mode);
+            if mode == Mode::Exclusive {
+                *current = mode;
+            }
+        }
+        let owners: Vec<String> = locks.keys().cloned().collect();
+
+        // Phase one of the locking: grow.
+        let replies = self
+            .broadcast(runtime, owners.iter(), |n| Request::Lock {
+                txn: id.clone(),
+                locks: locks[n].iter().map(|(k, m)| (*k, *m)).collect(),
+            })
+            .await;
+        let mut values = HashMap::<usize, Option<Value>>::new();
+        let mut failure: Option<Error> = None;
+        for reply in replies {
+            match reply {
+                Ok(Response::LockOk { values: v }) => values.extend(v),
+                Ok(_) => failure = Some(Error::Crash),
+                Err(e) => {
+                    let e = e.downcast_ref::<Error>().cloned();
+                    failure = Some(match e {
+                        Some(Error::TxnConflict) => Error::TxnConflict,
+                        _ => Error::TemporarilyUnavailable,
+                    });
+                }
+            }
+        }
+        if let Some(e) = failure {
+            self.finish(runtime, &id, &owners, false).await;
+            return Err(Box::new(e));
+        }
+
+        let mut result_txn: Vec<Op> = vec![];
+        let mut writes = BTreeMap::<String, HashMap<usize, Value>>::new();
+        for op in txn {
+            let current = values.get(&op.1).and_then(|value| value.as_ref());
+            let (op, write) = match op.apply(current) {
+                Ok(applied) => applied,
+                Err(e) => {
+                    self.finish(runtime, &id, &owners, false).await;
+                    return Err(e);
+                }
+            };
+            if let Some(value) = write {
+                values.insert(op.1, Some(value.clone()));
+                writes.entry(owner(op.1)).or_default().insert(op.1, value);
+            }
+            result_txn.push(op);
+        }
+
+        // Every owner prepares, including those with nothing to write, so
+        // none of the values the transaction read can have changed under a
+        // lock that expired.
+        let replies = self
+            .broadcast(runtime, owners.iter(), |n| Request::Prepare {
+                txn: id.clone(),
+                locks: locks[n].iter().map(|(k, m)| (*k, *m)).collect(),
+                writes: match writes.get(n) {
+                    Some(w) => w.iter().map(|(k, v)| (*k, v.clone())).collect(),
+                    None => vec![],
+                },
+            })
+            .await;
+        let prepared = replies.iter().all(|reply| reply.is_ok());
+        self.finish(runtime, &id, &owners, prepared).await;
+        if !prepared {
+            return Err(Box::new(Error::Abort));
+        }
+        Ok(result_txn)
+    }
+
+    async fn process(&self, runtime: Runtime, req: Message) -> Result<()> {
+        let msg: Result<Request> = req.body.as_obj();
+        match msg {
+            Ok(msg) => {
+                self.clock.observe(&req);
+                let response = self.handle(msg).await?;
+                runtime.reply(req, self.clock.stamp(response)).await
+            }
+            Err(_) => done(runtime, req),
+        }
+    }
+}
+
+/// Identifies a transaction and orders it by age: the time it started on
+/// its coordinator's clock, then the coordinator to break ties.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+struct TxnId(Timestamp, String);
+
+struct State {
+    data: HashMap<usize, Value>,
+    locks: LockTable<TxnId>,
+    /// Writes of prepared transactions, installed when they commit.
+    staged: HashMap<TxnId, Vec<(usize, Value)>>,
+}
+
+impl State {
+    fn new() -> Self {
+        Self {
+            data: HashMap::new(),
+            locks: LockTable::new(),
+            staged: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum Request {
+    Lock {
+        txn: TxnId,
+        locks: Vec<(usize, Mode)>,
+    },
+    Prepare {
+        txn: TxnId,
+        locks: Vec<(usize, Mode)>,
+        writes: Vec<(usize, Value)>,
+    },
+    Commit {
+        txn: TxnId,
+    },
+    Abort {
+        txn: TxnId,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+#[allow(clippy::enum_variant_names)]
+enum Response {
+    LockOk { values: Vec<(usize, Option<Value>)> },
+    PrepareOk {},
+    CommitOk {},
+    AbortOk {},
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn txn(ms: u64) -> TxnId {
+        TxnId(Timestamp(ms, 0), "n0".to_string())
+    }
+
+    fn lock(engine: &Serializable, txn: &TxnId, key: usize, mode: Mode) -> Result<Response> {
+        let locks = vec![(key, mode)];
+        block_on(engine.handle(Request::Lock {
+            txn: txn.clone(),
+            locks,
+        }))
+    }
+
+    fn prepare(engine: &Serializable, txn: &TxnId, locks: Vec<(usize, Mode)>) -> Result<Response> {
+        let writes = vec![];
+        block_on(engine.handle(Request::Prepare {
+            txn: txn.clone(),
+            locks,
+            writes,
+        }))
+    }
+
+    #[test]
+    fn prepare_fails_once_a_shared_lock_expired() {
+        let engine = Serializable::new();
+        let (reader, writer) = (txn(1), txn(2));
+        assert!(lock(&engine, &reader, 0, Mode::Shared).is_ok());
+        engine
+            .inner
+            .lock()
+            .unwrap()
+            .locks
+            .backdate(&reader, Duration::from_secs(60));
+        assert!(lock(&engine, &writer, 0, Mode::Exclusive).is_ok());
+
+        let prepared = prepare(&engine, &reader, vec![(0, Mode::Shared)]);
+        let e = prepared.err().unwrap();
+        assert!(matches!(e.downcast_ref::<Error>(), Some(Error::Abort)));
+        assert!(prepare(&engine, &writer, vec![(0, Mode::Exclusive)]).is_ok());
+    }
+
+    #[test]
+    fn commit_installs_the_prepared_writes_and_releases_the_locks() {
+        let engine = Serializable::new();
+        let (first, second) = (txn(1), txn(2));
+        assert!(lock(&engine, &first, 0, Mode::Exclusive).is_ok());
+        let writes = vec![(0, Value::Int(7))];
+        let req = Request::Prepare {
+            txn: first.clone(),
+            locks: vec![(0, Mode::Exclusive)],
+            writes,
+        };
+        assert!(block_on(engine.handle(req)).is_ok());
+        assert!(block_on(engine.handle(Request::Commit { txn: first })).is_ok());
+
+        let Ok(Response::LockOk { values }) = lock(&engine, &second, 0, Mode::Shared) else {
+            panic!("lock refused");
+        };
+        assert!(matches!(values[..], [(0, Some(Value::Int(7)))]));
+    }
+}